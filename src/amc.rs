@@ -1,4 +1,4 @@
-use xcb::{randr, Xid};
+use xcb::{randr, render, Xid};
 
 macro_rules! xcb_make_request {
     ($conn:expr, $req:expr) => {
@@ -6,12 +6,375 @@ macro_rules! xcb_make_request {
     };
 }
 
-pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+pub type Result<T> = std::result::Result<T, Error>;
 
-pub struct XCBStack<'a> {
-    pub conn: &'a xcb::Connection,
-    pub root_screen: &'a xcb::x::Screen,
-    pub root_window: &'a xcb::x::Window,
+#[derive(Debug)]
+pub enum Error {
+    /* The connection to the X server is gone, nothing will work until reconnecting */
+    Connection(xcb::ConnError),
+    /* The connection to the X server of the display was lost earlier and amc is waiting for it
+    to come back */
+    Disconnected(String),
+    /* The X server refused a request */
+    Protocol(xcb::ProtocolError),
+    /* A config file (or the state file) is malformed. Lines and columns start at 1, columns
+    and lengths count characters. `snippet` is the offending line as written. */
+    Config {
+        file: String,
+        line: usize,
+        column: usize,
+        length: usize,
+        snippet: String,
+        msg: String,
+    },
+    /* Several errors at once, like every mistake found in the config files */
+    Many(Vec<Error>),
+    /* The connected monitors can't do what's asked, like driving more monitors than there are
+    crtcs or using a mode a monitor doesn't have. Retrying won't help until they change. */
+    Resources(String),
+    Edid(EdidError),
+    Io(std::io::Error),
+    /* Anything else, e.g. asking for a setup that doesn't exist */
+    Other(String),
+}
+
+impl Error {
+    pub fn config(line: usize, column: usize, length: usize, snippet: &str, msg: &str) -> Error {
+        Error::Config {
+            file: String::new(),
+            line,
+            column,
+            length,
+            snippet: snippet.to_string(),
+            msg: msg.to_string(),
+        }
+    }
+
+    /* A single error out of `errors`, which shouldn't be empty */
+    pub fn many(errors: Vec<Error>) -> Error {
+        let mut flat = Vec::<Error>::with_capacity(errors.len());
+        for err in errors {
+            match err {
+                Error::Many(errors) => flat.extend(errors),
+                err => flat.push(err),
+            }
+        }
+
+        match flat.len() {
+            1 => flat.pop().unwrap(),
+            _ => Error::Many(flat),
+        }
+    }
+
+    /* Whether the connection to an X server was just lost, in any of the errors */
+    pub fn lost_connection(&self) -> bool {
+        self.errors()
+            .iter()
+            .any(|err| matches!(err, Error::Connection(_)))
+    }
+
+    /* Every error this is made of */
+    pub fn errors(&self) -> Vec<&Error> {
+        match self {
+            Error::Many(errors) => errors.iter().collect(),
+            err => vec![err],
+        }
+    }
+
+    /* Sets the file config errors happened in, other errors are left untouched */
+    pub fn in_file(self, path: &str) -> Error {
+        match self {
+            Error::Config {
+                line,
+                column,
+                length,
+                snippet,
+                msg,
+                ..
+            } => Error::Config {
+                file: path.to_string(),
+                line,
+                column,
+                length,
+                snippet,
+                msg,
+            },
+            Error::Many(errors) => {
+                Error::Many(errors.into_iter().map(|err| err.in_file(path)).collect())
+            }
+            err => err,
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Connection(err) => write!(f, "Lost the connection to the X server ({})", err),
+            Error::Disconnected(display) => write!(
+                f,
+                "{}: amc is waiting for the X server to come back",
+                display
+            ),
+            Error::Protocol(err) => write!(f, "The X server refused a request ({})", err),
+            Error::Config {
+                file,
+                line,
+                column,
+                length,
+                snippet,
+                msg,
+            } => {
+                match file.is_empty() {
+                    true => write!(f, "{}:{}: {}", line, column, msg)?,
+                    false => write!(f, "{}:{}:{}: {}", file, line, column, msg)?,
+                }
+
+                if snippet.is_empty() {
+                    return Ok(());
+                }
+
+                /* Like compilers do it, the line with the part the error is about underlined.
+                Tabs become spaces so that the carets end up below the right characters. */
+                let gutter = " ".repeat(line.to_string().len());
+                write!(
+                    f,
+                    "\n{} |\n{} | {}\n{} | {}{}",
+                    gutter,
+                    line,
+                    snippet.replace('\t', " "),
+                    gutter,
+                    " ".repeat(column.saturating_sub(1)),
+                    "^".repeat(std::cmp::max(*length, 1))
+                )
+            }
+            Error::Many(errors) => write!(
+                f,
+                "{}",
+                errors
+                    .iter()
+                    .map(|err| err.to_string())
+                    .collect::<Vec<String>>()
+                    .join("\n\n")
+            ),
+            Error::Resources(msg) => write!(f, "{}", msg),
+            Error::Edid(err) => write!(f, "{}", err),
+            Error::Io(err) => write!(f, "{}", err),
+            Error::Other(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<xcb::Error> for Error {
+    fn from(err: xcb::Error) -> Error {
+        match err {
+            xcb::Error::Connection(err) => Error::Connection(err),
+            xcb::Error::Protocol(err) => Error::Protocol(err),
+        }
+    }
+}
+
+impl From<xcb::ConnError> for Error {
+    fn from(err: xcb::ConnError) -> Error {
+        Error::Connection(err)
+    }
+}
+
+impl From<xcb::ProtocolError> for Error {
+    fn from(err: xcb::ProtocolError) -> Error {
+        Error::Protocol(err)
+    }
+}
+
+impl From<EdidError> for Error {
+    fn from(err: EdidError) -> Error {
+        Error::Edid(err)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<String> for Error {
+    fn from(msg: String) -> Error {
+        Error::Other(msg)
+    }
+}
+
+impl From<&str> for Error {
+    fn from(msg: &str) -> Error {
+        Error::Other(msg.to_string())
+    }
+}
+
+#[derive(Debug)]
+pub enum EdidError {
+    /* The EDID output property is supposed to be an array of 8 bit integers */
+    InvalidProperty {
+        output: String,
+        r#type: xcb::x::Atom,
+        format: u8,
+    },
+}
+
+impl std::fmt::Display for EdidError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EdidError::InvalidProperty {
+                output,
+                r#type,
+                format,
+            } => write!(
+                f,
+                "The EDID of output '{}' is not made of bytes (type {:?}, format {})",
+                output, r#type, format
+            ),
+        }
+    }
+}
+
+impl std::error::Error for EdidError {}
+
+pub struct XCBStack {
+    pub conn: xcb::Connection,
+    /* The display's name without the screen number, e.g. ':0' */
+    pub display: String,
+    pub screen_num: i32,
+    pub root_window: xcb::x::Window,
+    /* The screen's size in millimeters, as last reported by the X server. The connection setup
+    only has the one from when we connected, so it's kept up to date from the screen change
+    events, see XCBStack::screen_mm */
+    screen_mm: std::cell::Cell<(u32, u32)>,
+}
+
+impl XCBStack {
+    /* Owns the connection so the whole stack can be thrown away and rebuilt when the X server
+    goes away. Without `screen_num`, the screen the display name points at is used. */
+    pub fn connect(display: Option<&str>, screen_num: Option<i32>) -> Result<XCBStack> {
+        let (conn, default_screen_num) = xcb::Connection::connect(display)?;
+        let screen_num = screen_num.unwrap_or(default_screen_num);
+
+        let root_window = match conn.get_setup().roots().nth(screen_num as usize) {
+            Some(screen) => screen.root(),
+            None => {
+                return Err(format_args!("The X server has no screen {}", screen_num)
+                    .to_string()
+                    .into())
+            }
+        };
+
+        // xcb went with $DISPLAY if we didn't give it a name
+        let display = match display {
+            Some(x) => x.to_string(),
+            None => std::env::var("DISPLAY").unwrap_or_default(),
+        };
+
+        let screen = conn.get_setup().roots().nth(screen_num as usize).unwrap();
+        let screen_mm = (
+            screen.width_in_millimeters() as u32,
+            screen.height_in_millimeters() as u32,
+        );
+
+        conn.send_and_check_request(&randr::SelectInput {
+            window: root_window,
+            enable: randr::NotifyMask::SCREEN_CHANGE,
+        })?;
+
+        Ok(XCBStack {
+            conn,
+            display: split_display_name(&display).0,
+            screen_num,
+            root_window,
+            screen_mm: std::cell::Cell::new(screen_mm),
+        })
+    }
+
+    /* The screen's current size in millimeters, once caught up with the screen changes the X
+    server told us about (ours included) */
+    pub fn screen_mm(&self) -> Result<(u32, u32)> {
+        while let Some(event) = self.conn.poll_for_event()? {
+            if let xcb::Event::RandR(randr::Event::ScreenChangeNotify(ev)) = event {
+                if ev.root() == self.root_window {
+                    self.screen_mm
+                        .set((ev.mwidth() as u32, ev.mheight() as u32));
+                }
+            }
+        }
+
+        Ok(self.screen_mm.get())
+    }
+
+    /* A stack for each screen of `display`, or only for the one it names if it has a screen
+    number (e.g. ':0.1') */
+    pub fn connect_all(display: Option<&str>) -> Result<Vec<XCBStack>> {
+        let display_name = match display {
+            Some(x) => x.to_string(),
+            None => std::env::var("DISPLAY").unwrap_or_default(),
+        };
+
+        if let (_, Some(screen_num)) = split_display_name(&display_name) {
+            return Ok(vec![XCBStack::connect(display, Some(screen_num))?]);
+        }
+
+        let first = XCBStack::connect(display, Some(0))?;
+        let screen_count = first.conn.get_setup().roots().count() as i32;
+
+        let mut stacks = vec![first];
+        for screen_num in 1..screen_count {
+            stacks.push(XCBStack::connect(display, Some(screen_num))?);
+        }
+
+        Ok(stacks)
+    }
+
+    /* The display and screen, e.g. ':0.1' */
+    pub fn name(&self) -> String {
+        format_args!("{}.{}", self.display, self.screen_num).to_string()
+    }
+
+    pub fn root_screen(&self) -> &xcb::x::Screen {
+        self.conn
+            .get_setup()
+            .roots()
+            .nth(self.screen_num as usize)
+            .unwrap()
+    }
+}
+
+/* The name of the machine amc runs on, None if it can't be had */
+pub fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+
+    if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } != 0 {
+        return None;
+    }
+
+    let len = buf.iter().position(|&c| c == 0).unwrap_or(buf.len());
+    match std::str::from_utf8(&buf[..len]) {
+        Ok(name) if !name.is_empty() => Some(name.to_string()),
+        _ => None,
+    }
+}
+
+/* Splits an X display name like 'host:0.1' into the display ('host:0') and screen (1) */
+pub fn split_display_name(name: &str) -> (String, Option<i32>) {
+    let colon = name.rfind(':').map(|i| i + 1).unwrap_or(0);
+
+    match name[colon..].split_once('.') {
+        Some((display_num, screen_num)) => match screen_num.parse::<i32>() {
+            Ok(screen_num) => (
+                format_args!("{}{}", &name[..colon], display_num).to_string(),
+                Some(screen_num),
+            ),
+            Err(_) => (name.to_string(), None),
+        },
+        None => (name.to_string(), None),
+    }
 }
 
 pub struct RandrOutputInfo {
@@ -99,11 +462,11 @@ impl RandrOutputInfo {
             if i < self.info.num_preferred().into() {
                 dist = 0;
             } else if self.info.mm_height() > 0 {
-                dist = (1000 * (xstack.root_screen.height_in_pixels() as i32)
-                    / (xstack.root_screen.height_in_millimeters() as i32))
+                dist = (1000 * (xstack.root_screen().height_in_pixels() as i32)
+                    / (xstack.root_screen().height_in_millimeters() as i32))
                     - (1000 * (mode_info.height as i32) / (self.info.mm_height() as i32));
             } else {
-                dist = (xstack.root_screen.height_in_pixels() as i32) - (mode_info.height as i32);
+                dist = (xstack.root_screen().height_in_pixels() as i32) - (mode_info.height as i32);
             }
 
             if dist < 0 {
@@ -133,22 +496,145 @@ impl RandrOutputInfo {
     }
 }
 
+#[derive(Debug)]
+pub struct MonitorMode {
+    pub mode: randr::Mode,
+    pub width: u16,
+    pub height: u16,
+    /* In Hz, 0 if the mode doesn't say */
+    pub refresh: f64,
+    /* Whether the monitor lists it among its preferred modes */
+    pub preferred: bool,
+}
+
+impl MonitorMode {
+    fn from_mode_info(
+        mode: randr::Mode,
+        mode_info: &randr::ModeInfo,
+        preferred: bool,
+    ) -> MonitorMode {
+        // Same as xrandr, doublescan modes draw every line twice and interlaced ones every other
+        let mut vtotal = mode_info.vtotal as f64;
+        if mode_info.mode_flags.contains(randr::ModeFlag::DOUBLE_SCAN) {
+            vtotal *= 2.0;
+        }
+        if mode_info.mode_flags.contains(randr::ModeFlag::INTERLACE) {
+            vtotal /= 2.0;
+        }
+
+        let refresh = match mode_info.htotal != 0 && vtotal != 0.0 {
+            true => mode_info.dot_clock as f64 / (mode_info.htotal as f64 * vtotal),
+            false => 0.0,
+        };
+
+        MonitorMode {
+            mode,
+            width: mode_info.width,
+            height: mode_info.height,
+            refresh,
+            preferred,
+        }
+    }
+}
+
+/* The parts of an EDID that help a human tell monitors apart */
+#[derive(Debug)]
+pub struct EdidInfo {
+    /* Three letter PNP id of the manufacturer, e.g. 'DEL' */
+    pub vendor: String,
+    pub product_code: u16,
+    /* From the monitor name descriptor, if there is one */
+    pub model: Option<String>,
+    /* From the serial number descriptor, or the numeric serial if there is none */
+    pub serial: Option<String>,
+}
+
+impl EdidInfo {
+    /* None if it doesn't look like an EDID at all */
+    pub fn parse(edid: &[u8]) -> Option<EdidInfo> {
+        if edid.len() < 128 || edid[0..8] != [0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00] {
+            return None;
+        }
+
+        // Three 5 bit letters where 1 is 'A'
+        let vendor_bits = u16::from_be_bytes([edid[8], edid[9]]);
+        let vendor = [10, 5, 0]
+            .iter()
+            .map(|shift| (b'@' + ((vendor_bits >> shift) & 0x1f) as u8) as char)
+            .collect::<String>();
+
+        let product_code = u16::from_le_bytes([edid[10], edid[11]]);
+        let serial_n = u32::from_le_bytes([edid[12], edid[13], edid[14], edid[15]]);
+
+        let mut model = None;
+        let mut serial = None;
+
+        // The four 18 byte descriptors, the ones holding text start with a zero pixel clock
+        for descriptor in edid[54..126].chunks(18) {
+            if descriptor[0..3] != [0, 0, 0] {
+                continue;
+            }
+
+            // Text is padded with a newline followed by spaces
+            let text = String::from_utf8_lossy(&descriptor[5..18])
+                .split('\n')
+                .next()
+                .unwrap_or("")
+                .trim()
+                .to_string();
+
+            match descriptor[3] {
+                0xfc => model = Some(text),
+                0xff => serial = Some(text),
+                _ => (),
+            }
+        }
+
+        if serial.is_none() && serial_n != 0 {
+            serial = Some(serial_n.to_string());
+        }
+
+        Some(EdidInfo {
+            vendor,
+            product_code,
+            model,
+            serial,
+        })
+    }
+}
+
 #[derive(Debug)]
 pub struct MonitorCrtcConfig {
     pub x: i16,
     pub y: i16,
     pub rot: randr::Rotation,
+    pub mode: randr::Mode,
+    pub scale: f64,
+    /* Every output driven by the crtc, not only this monitor's */
+    pub outputs: Vec<randr::Output>,
 }
 
 #[derive(Debug)]
 pub struct Monitor {
+    /* Hash of the EDID, or of the connector name and modes for monitors without one */
     pub id: u32,
     pub name: String,
+    pub edid: Option<Vec<u8>>,
+    /* Why the EDID the output exposes couldn't be used, in which case it's identified like a
+    monitor without one */
+    pub edid_error: Option<EdidError>,
     /* The monitor's current configuration. If it is unconfigured this is None */
     pub crtc_config: Option<MonitorCrtcConfig>,
     pub output: randr::Output,
     pub mode_best: randr::Mode,
-    pub crtc_slot: randr::Crtc,
+    /* The crtc currently driving the monitor, if any */
+    pub crtc: Option<randr::Crtc>,
+    /* Every crtc able to drive the monitor's output */
+    pub crtcs: Vec<randr::Crtc>,
+    /* Outputs that can be driven by the same crtc as this monitor's */
+    pub clones: Vec<randr::Output>,
+    /* Every mode the monitor supports, in the order the output lists them */
+    pub modes: Vec<MonitorMode>,
     pub w: u16,
     pub h: u16,
     pub w_mm: u32,
@@ -160,32 +646,49 @@ impl Monitor {
         xstack: &XCBStack,
         id: u32,
         output: &RandrOutputInfo,
-        crtc_slot: Option<&randr::Crtc>,
         modes: &[randr::ModeInfo],
-    ) -> Result<(Monitor, bool)> {
+    ) -> Result<Monitor> {
         let (best_mode, width, height) = output.get_best_mode(xstack, modes)?;
 
-        if output.info.crtc().is_none() {
-            if crtc_slot.is_none() {
-                return Err("Output needed a new crtc as it was not configured, but there were none left. Can your GPU handle this many monitors?".into());
-            }
+        let mon_modes = output
+            .info
+            .modes()
+            .iter()
+            .enumerate()
+            .filter_map(|(i, mode)| {
+                modes
+                    .iter()
+                    .find(|mode_info| mode_info.id == mode.resource_id())
+                    .map(|mode_info| {
+                        MonitorMode::from_mode_info(
+                            *mode,
+                            mode_info,
+                            i < output.info.num_preferred() as usize,
+                        )
+                    })
+            })
+            .collect::<Vec<MonitorMode>>();
 
-            Ok((
-                Monitor {
-                    id,
-                    name: String::from_utf8(output.info.name().to_vec()).unwrap(),
-                    crtc_config: None,
-                    output: output.xres,
-                    crtc_slot: *crtc_slot.unwrap(),
-                    mode_best: best_mode,
-                    w: width,
-                    h: height,
-                    w_mm: output.info.mm_width(),
-                    h_mm: output.info.mm_height(),
-                },
-                true,
-            ))
-        } else {
+        let mut mon = Monitor {
+            id,
+            name: String::from_utf8_lossy(output.info.name()).to_string(),
+            edid: None,
+            edid_error: None,
+            crtc_config: None,
+            output: output.xres,
+            mode_best: best_mode,
+            crtc: None,
+            crtcs: output.info.crtcs().to_vec(),
+            clones: output.info.clones().to_vec(),
+            modes: mon_modes,
+            w: width,
+            h: height,
+            w_mm: output.info.mm_width(),
+            h_mm: output.info.mm_height(),
+        };
+
+        // Unconfigured monitors get a crtc once we know which ones are going to be used
+        if !output.info.crtc().is_none() {
             let crtc_info = xcb_make_request!(
                 xstack.conn,
                 &randr::GetCrtcInfo {
@@ -194,26 +697,62 @@ impl Monitor {
                 }
             );
 
-            Ok((
-                Monitor {
-                    id,
-                    name: String::from_utf8(output.info.name().to_vec()).unwrap(),
-                    output: output.xres,
-                    crtc_config: Some(MonitorCrtcConfig {
-                        x: crtc_info.x(),
-                        y: crtc_info.y(),
-                        rot: crtc_info.rotation(),
-                    }),
-                    crtc_slot: output.info.crtc(),
-                    mode_best: best_mode,
-                    w: width,
-                    h: height,
-                    w_mm: output.info.mm_width(),
-                    h_mm: output.info.mm_height(),
-                },
-                false,
-            ))
+            mon.crtc = Some(output.info.crtc());
+            mon.crtc_config = Some(MonitorCrtcConfig {
+                x: crtc_info.x(),
+                y: crtc_info.y(),
+                rot: crtc_info.rotation(),
+                mode: crtc_info.mode(),
+                scale: crtc_scale(xstack, output.info.crtc())?,
+                outputs: crtc_info.outputs().to_vec(),
+            });
         }
+
+        Ok(mon)
+    }
+
+    pub fn edid_info(&self) -> Option<EdidInfo> {
+        self.edid.as_deref().and_then(EdidInfo::parse)
+    }
+
+    /* Whether it's a laptop's built-in panel, going by the connector names drivers give those */
+    pub fn is_internal(&self) -> bool {
+        ["eDP", "LVDS", "DSI"]
+            .iter()
+            .any(|prefix| self.name.starts_with(prefix))
+    }
+
+    pub fn find_mode(&self, mode: randr::Mode) -> Option<&MonitorMode> {
+        self.modes.iter().find(|mon_mode| mon_mode.mode == mode)
+    }
+
+    /* None if the monitor doesn't know its physical size */
+    pub fn dpi(&self) -> Option<f64> {
+        if self.w_mm == 0 || self.h_mm == 0 {
+            return None;
+        }
+
+        // Modes and physical sizes are both unrotated, so this holds for any rotation
+        Some((self.w as f64) * 25.4 / (self.w_mm as f64))
+    }
+
+    /* Projectors, virtual outputs and the like might not have an EDID, these get identified by
+    their connector name and available modes instead. Not as good since the connector name is
+    what we're trying not to depend on, but it's still better than not managing them at all. */
+    fn make_fallback_id(output: &RandrOutputInfo, modes: &[randr::ModeInfo]) -> u32 {
+        let mut bytes = output.info.name().to_vec();
+
+        for mode in output.info.modes() {
+            if let Some(mode_info) = modes
+                .iter()
+                .find(|mode_info| mode_info.id == mode.resource_id())
+            {
+                bytes.extend(mode_info.width.to_le_bytes());
+                bytes.extend(mode_info.height.to_le_bytes());
+            }
+        }
+
+        Self::make_id_from_edid(&bytes)
     }
 
     fn make_id_from_edid(edid_bytes: &[u8]) -> u32 {
@@ -233,25 +772,15 @@ impl Monitor {
         let screen_resources = xcb_make_request!(
             xstack.conn,
             &randr::GetScreenResources {
-                window: *xstack.root_window,
+                window: xstack.root_window,
             }
         );
 
         let outputs = RandrOutputInfo::get_all_and_remove_dangling_crtcs(
-            xstack.conn,
+            &xstack.conn,
             screen_resources.outputs(),
         )?;
 
-        let mut free_crtcs: Vec<randr::Crtc> = Vec::from(screen_resources.crtcs())
-            .into_iter()
-            .filter(|&crtc| {
-                outputs
-                    .iter()
-                    .find(|output| output.info.crtc() == crtc)
-                    .is_none()
-            })
-            .collect();
-
         let mut connected_mons = Vec::<Monitor>::with_capacity(screen_resources.outputs().len());
 
         for output in outputs {
@@ -266,7 +795,8 @@ impl Monitor {
                 }
             );
 
-            let mut mon_id: u32 = 0;
+            let mut edid: Option<Vec<u8>> = None;
+            let mut edid_error: Option<EdidError> = None;
             for atom in output_props.atoms() {
                 let atom_name =
                     xcb_make_request!(xstack.conn, &xcb::x::GetAtomName { atom: *atom });
@@ -288,72 +818,1034 @@ impl Monitor {
                     }
                 );
 
-                assert!(edid_data.r#type() == xcb::x::ATOM_INTEGER && edid_data.format() == 8);
+                /* One odd driver shouldn't keep every other monitor from being found, so the
+                output is handled like one without an EDID, the error left for the caller to
+                report */
+                if edid_data.r#type() != xcb::x::ATOM_INTEGER || edid_data.format() != 8 {
+                    edid_error = Some(EdidError::InvalidProperty {
+                        output: String::from_utf8_lossy(output.info.name()).to_string(),
+                        r#type: edid_data.r#type(),
+                        format: edid_data.format(),
+                    });
+                    break;
+                }
 
-                mon_id = Self::make_id_from_edid(edid_data.data::<u8>());
+                // Some drivers expose the property but leave it empty
+                if !edid_data.data::<u8>().is_empty() {
+                    edid = Some(edid_data.data::<u8>().to_vec());
+                }
                 break;
             }
 
-            assert!(mon_id != 0);
-
-            match Self::build(
-                xstack,
-                mon_id,
-                &output,
-                match free_crtcs.len() {
-                    0 => None,
-                    _ => Some(&free_crtcs[0]),
-                },
-                screen_resources.modes(),
-            ) {
-                Ok((mon, consumed_crtc)) => {
-                    if consumed_crtc {
-                        free_crtcs.remove(0);
-                    }
+            let mon_id = match &edid {
+                Some(edid) => Self::make_id_from_edid(edid),
+                None => Self::make_fallback_id(&output, screen_resources.modes()),
+            };
 
-                    connected_mons.push(mon);
-                }
-                Err(err) => return Err(err),
-            }
+            let mut mon = Self::build(xstack, mon_id, &output, screen_resources.modes())?;
+            mon.edid = edid;
+            mon.edid_error = edid_error;
+            connected_mons.push(mon);
         }
 
         return Ok(connected_mons);
     }
 
-    pub fn apply_config(&self, xstack: &XCBStack, conf: &MonitorConfig) -> Result<bool> {
-        let configure = match &self.crtc_config {
-            Some(cur_config) => {
-                conf.x != cur_config.x || conf.y != cur_config.y || conf.rot != cur_config.rot
+    /* Whether both monitors can be driven by a single crtc, showing the same thing. Only done
+    when they agree on the mode, so mirroring never costs a monitor the mode it's meant to use. */
+    pub fn can_share_crtc_with(
+        &self,
+        mode: randr::Mode,
+        other: &Monitor,
+        other_mode: randr::Mode,
+    ) -> bool {
+        mode == other_mode
+            && self.clones.contains(&other.output)
+            && other.clones.contains(&self.output)
+    }
+
+    /* The mode `conf` asks for: the best one unless it names a size and/or a refresh rate.
+    Among modes of the right size, the preferred one wins, then the fastest. */
+    pub fn mode_for(&self, conf: &MonitorConfig) -> Result<&MonitorMode> {
+        if conf.mode.is_none() && conf.rate.is_none() {
+            if let Some(mode) = self.find_mode(self.mode_best) {
+                return Ok(mode);
             }
-            None => true,
+        }
+
+        let (w, h) = conf.mode.unwrap_or((self.w, self.h));
+        let sized = self
+            .modes
+            .iter()
+            .filter(|mode| mode.width == w && mode.height == h);
+
+        let mode = match conf.rate {
+            Some(rate) => sized
+                .min_by(|a, b| {
+                    (a.refresh - rate)
+                        .abs()
+                        .total_cmp(&(b.refresh - rate).abs())
+                })
+                // Refresh rates are never exact, 59.95Hz is as good as 60
+                .filter(|mode| (mode.refresh - rate).abs() < 1.0),
+            None => sized.max_by(|a, b| {
+                a.preferred
+                    .cmp(&b.preferred)
+                    .then(a.refresh.total_cmp(&b.refresh))
+            }),
         };
 
+        match (mode, conf.rate) {
+            (Some(mode), _) => Ok(mode),
+            (None, Some(rate)) => Err(Error::Resources(
+                format_args!(
+                    "Monitor {} ({:x}) has no {}x{} mode at {}Hz",
+                    self.name, self.id, w, h, rate
+                )
+                .to_string(),
+            )),
+            (None, None) => Err(Error::Resources(
+                format_args!(
+                    "Monitor {} ({:x}) has no {}x{} mode",
+                    self.name, self.id, w, h
+                )
+                .to_string(),
+            )),
+        }
+    }
+
+    /* The config `crtc` needs for this monitor to match `conf` in `mode` while driving
+    `outputs` (this monitor's and those of its clones), None if it already does. When `force`
+    is set the config is returned even if the monitor already matches. */
+    pub fn crtc_config_for(
+        &self,
+        conf: &MonitorConfig,
+        mode: &MonitorMode,
+        crtc: randr::Crtc,
+        outputs: &[randr::Output],
+        force: bool,
+    ) -> Option<CrtcConfig> {
+        let configure = force
+            || self.crtc != Some(crtc)
+            || match &self.crtc_config {
+                /* The rotation also holds the reflection bits. A crtc stuck on another mode
+                (e.g. a low resolution picked before the monitor's EDID was read) or shared
+                with outputs other than its clones also needs fixing */
+                Some(cur_config) => {
+                    conf.x != cur_config.x
+                        || conf.y != cur_config.y
+                        || conf.rot != cur_config.rot
+                        || conf.scale != cur_config.scale
+                        || cur_config.mode != mode.mode
+                        || cur_config.outputs.len() != outputs.len()
+                        || !outputs.iter().all(|o| cur_config.outputs.contains(o))
+                }
+                None => true,
+            };
+
         if !configure {
-            return Ok(false);
+            return None;
         }
 
-        xcb_make_request!(
+        let (w, h) = conf.extent(mode);
+
+        Some(CrtcConfig {
+            crtc,
+            x: conf.x,
+            y: conf.y,
+            w,
+            h,
+            mode: mode.mode,
+            rot: conf.rot,
+            scale: conf.scale,
+            outputs: outputs.to_vec(),
+        })
+    }
+}
+
+/* Everything SetCrtcConfig needs, used both for new configs and to remember old ones */
+#[derive(Debug, Clone, PartialEq)]
+pub struct CrtcConfig {
+    pub crtc: randr::Crtc,
+    pub x: i16,
+    pub y: i16,
+    /* The area covered on the screen, so already rotated */
+    pub w: u16,
+    pub h: u16,
+    pub mode: randr::Mode,
+    pub rot: randr::Rotation,
+    pub scale: f64,
+    pub outputs: Vec<randr::Output>,
+}
+
+impl CrtcConfig {
+    pub fn disabled(crtc: randr::Crtc) -> CrtcConfig {
+        CrtcConfig {
+            crtc,
+            x: 0,
+            y: 0,
+            w: 0,
+            h: 0,
+            mode: randr::Mode::none(),
+            rot: randr::Rotation::ROTATE_0,
+            scale: 1.0,
+            outputs: Vec::new(),
+        }
+    }
+
+    pub fn is_disabled(&self) -> bool {
+        self.mode.is_none()
+    }
+
+    fn fits_in(&self, screen: &ScreenSize) -> bool {
+        self.is_disabled()
+            || (self.x >= 0
+                && self.y >= 0
+                && (self.x as u32) + (self.w as u32) <= screen.width as u32
+                && (self.y as u32) + (self.h as u32) <= screen.height as u32)
+    }
+
+    fn get(xstack: &XCBStack, crtc: randr::Crtc) -> Result<CrtcConfig> {
+        let crtc_info = xcb_make_request!(
+            xstack.conn,
+            &randr::GetCrtcInfo {
+                crtc,
+                config_timestamp: xcb::x::CURRENT_TIME,
+            }
+        );
+
+        Ok(CrtcConfig {
+            crtc,
+            x: crtc_info.x(),
+            y: crtc_info.y(),
+            w: crtc_info.width(),
+            h: crtc_info.height(),
+            mode: crtc_info.mode(),
+            rot: crtc_info.rotation(),
+            scale: crtc_scale(xstack, crtc)?,
+            outputs: crtc_info.outputs().to_vec(),
+        })
+    }
+
+    fn set(&self, xstack: &XCBStack) -> Result<()> {
+        /* The transform is only pending until the next SetCrtcConfig, which is when it kicks
+        in. It's only sent when the scale changes, as some X servers (Xvfb, Xvnc...) can't do
+        transforms at all and refuse it, and so that transforms and filters set with xrandr
+        are left alone otherwise. Enabling a crtc compares with the transform it was left with,
+        so a disabled one doesn't need its own reset. */
+        if !self.is_disabled() && self.scale != crtc_scale(xstack, self.crtc)? {
+            self.set_scale(xstack)?;
+        }
+
+        let reply = xcb_make_request!(
             xstack.conn,
             &randr::SetCrtcConfig {
-                crtc: self.crtc_slot,
+                crtc: self.crtc,
                 timestamp: xcb::x::CURRENT_TIME,
                 config_timestamp: xcb::x::CURRENT_TIME,
-                x: conf.x,
-                y: conf.y,
-                mode: self.mode_best,
-                rotation: conf.rot,
-                outputs: &[self.output],
+                x: self.x,
+                y: self.y,
+                mode: self.mode,
+                rotation: self.rot,
+                outputs: &self.outputs,
+            }
+        );
+
+        if reply.status() != randr::SetConfig::Success {
+            return Err(Error::Resources(
+                format_args!(
+                    "Could not configure crtc {:?} ({:?})",
+                    self.crtc,
+                    reply.status()
+                )
+                .to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /* Sets up the crtc to scale its mode by `self.scale`, see set */
+    fn set_scale(&self, xstack: &XCBStack) -> Result<()> {
+        let scale = (self.scale * 65536.0).round() as render::Fixed;
+        xstack
+            .conn
+            .send_and_check_request(&randr::SetCrtcTransform {
+                crtc: self.crtc,
+                transform: render::Transform {
+                    matrix11: scale,
+                    matrix12: 0,
+                    matrix13: 0,
+                    matrix21: 0,
+                    matrix22: scale,
+                    matrix23: 0,
+                    matrix31: 0,
+                    matrix32: 0,
+                    matrix33: 65536,
+                },
+                filter_name: match self.scale == 1.0 {
+                    true => b"nearest",
+                    false => b"bilinear",
+                },
+                filter_params: &[],
+            })?;
+
+        Ok(())
+    }
+}
+
+/* How the screen's size in millimeters (and thus the dpi clients see) gets derived from
+its size in pixels. There's no right answer for a screen made of several monitors with
+gaps between them, so this is left to the user. */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DpiPolicy {
+    /* The dpi of the primary monitor */
+    Primary,
+    /* The dpi of each monitor averaged over the pixels they cover */
+    Average,
+    Fixed(f64),
+}
+
+/* Used whenever monitors don't report their physical size, like most projectors */
+pub const FALLBACK_DPI: f64 = 96.0;
+
+impl DpiPolicy {
+    /* `mons` should have the primary monitor first */
+    pub fn dpi(&self, mons: &[&Monitor]) -> f64 {
+        match self {
+            DpiPolicy::Fixed(dpi) => *dpi,
+            DpiPolicy::Primary => mons
+                .first()
+                .and_then(|mon| mon.dpi())
+                .unwrap_or(FALLBACK_DPI),
+            DpiPolicy::Average => {
+                let mut dpi_sum = 0.0;
+                let mut pixels = 0.0;
+
+                for mon in mons {
+                    if let Some(dpi) = mon.dpi() {
+                        dpi_sum += dpi * (mon.w as f64) * (mon.h as f64);
+                        pixels += (mon.w as f64) * (mon.h as f64);
+                    }
+                }
+
+                match pixels > 0.0 {
+                    true => dpi_sum / pixels,
+                    false => FALLBACK_DPI,
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScreenSize {
+    pub width: u16,
+    pub height: u16,
+    pub mm_width: u32,
+    pub mm_height: u32,
+}
+
+impl ScreenSize {
+    pub fn from_dpi(width: u16, height: u16, dpi: f64) -> ScreenSize {
+        ScreenSize {
+            width,
+            height,
+            mm_width: ((width as f64) * 25.4 / dpi).round() as u32,
+            mm_height: ((height as f64) * 25.4 / dpi).round() as u32,
+        }
+    }
+
+    pub fn get(xstack: &XCBStack) -> Result<ScreenSize> {
+        let geometry = xcb_make_request!(
+            xstack.conn,
+            &xcb::x::GetGeometry {
+                drawable: xcb::x::Drawable::Window(xstack.root_window),
+            }
+        );
+
+        // There's no request giving back the current size in millimeters, see XCBStack::screen_mm
+        let (mm_width, mm_height) = xstack.screen_mm()?;
+        Ok(ScreenSize {
+            width: geometry.width(),
+            height: geometry.height(),
+            mm_width,
+            mm_height,
+        })
+    }
+
+    fn set(&self, xstack: &XCBStack) -> Result<()> {
+        xstack.conn.send_and_check_request(&randr::SetScreenSize {
+            window: xstack.root_window,
+            width: self.width,
+            height: self.height,
+            mm_width: self.mm_width,
+            mm_height: self.mm_height,
+        })?;
+
+        // Its screen change event may come later than the next ScreenSize::get
+        xstack.screen_mm.set((self.mm_width, self.mm_height));
+
+        Ok(())
+    }
+}
+
+/* The scale of the transform a crtc is set up with, 1 if it isn't scaled. Only plain scaling
+(what amc and 'xrandr --scale' do) is recognized, anything fancier counts as unscaled. */
+fn crtc_scale(xstack: &XCBStack, crtc: randr::Crtc) -> Result<f64> {
+    let transform =
+        xcb_make_request!(xstack.conn, &randr::GetCrtcTransform { crtc }).current_transform();
+
+    if transform.matrix11 != transform.matrix22
+        || transform.matrix33 != 65536
+        || transform.matrix11 <= 0
+    {
+        return Ok(1.0);
+    }
+
+    Ok(transform.matrix11 as f64 / 65536.0)
+}
+
+pub fn get_primary_output(xstack: &XCBStack) -> Result<randr::Output> {
+    Ok(xcb_make_request!(
+        xstack.conn,
+        &randr::GetOutputPrimary {
+            window: xstack.root_window,
+        }
+    )
+    .output())
+}
+
+pub fn set_primary_output(xstack: &XCBStack, output: randr::Output) -> Result<()> {
+    xstack
+        .conn
+        .send_and_check_request(&randr::SetOutputPrimary {
+            window: xstack.root_window,
+            output,
+        })?;
+
+    Ok(())
+}
+
+/* Merges `resources` into the root window's RESOURCE_MANAGER property (what xrdb writes to and
+what Xlib based clients read their resources from when starting up). Existing resources with
+the same name are replaced, everything else is kept as is. */
+pub fn merge_xresources(xstack: &XCBStack, resources: &[(String, String)]) -> Result<()> {
+    if resources.is_empty() {
+        return Ok(());
+    }
+
+    let resource_manager = xcb_make_request!(
+        xstack.conn,
+        &xcb::x::InternAtom {
+            only_if_exists: false,
+            name: b"RESOURCE_MANAGER",
+        }
+    )
+    .atom();
+
+    let property = xcb_make_request!(
+        xstack.conn,
+        &xcb::x::GetProperty {
+            delete: false,
+            window: xstack.root_window,
+            property: resource_manager,
+            r#type: xcb::x::ATOM_STRING,
+            long_offset: 0,
+            long_length: u32::MAX / 4,
+        }
+    );
+
+    let old_content = String::from_utf8_lossy(property.value::<u8>()).to_string();
+    let new_content = merge_xresources_content(&old_content, resources);
+
+    if new_content == old_content {
+        return Ok(());
+    }
+
+    xstack
+        .conn
+        .send_and_check_request(&xcb::x::ChangeProperty {
+            mode: xcb::x::PropMode::Replace,
+            window: xstack.root_window,
+            property: resource_manager,
+            r#type: xcb::x::ATOM_STRING,
+            data: new_content.as_bytes(),
+        })?;
+
+    Ok(())
+}
+
+/* Splits the content of RESOURCE_MANAGER into its resources. A line ending with a backslash
+continues on the next one (as xrdb writes long values), so those lines are kept together, as
+written. */
+fn split_xresources(content: &str) -> Vec<String> {
+    let mut entries = Vec::<String>::new();
+    let mut continued = false;
+
+    for line in content.lines() {
+        match (continued, entries.last_mut()) {
+            (true, Some(entry)) => {
+                entry.push('\n');
+                entry.push_str(line);
+            }
+            _ => entries.push(line.to_string()),
+        }
+
+        // An even number of backslashes is escaped backslashes, not a continuation
+        continued = line.chars().rev().take_while(|&c| c == '\\').count() % 2 == 1;
+    }
+
+    entries
+}
+
+fn merge_xresources_content(content: &str, resources: &[(String, String)]) -> String {
+    let mut entries = split_xresources(content);
+
+    for (name, value) in resources {
+        let new_entry = format_args!("{}:\t{}", name, value).to_string();
+
+        match entries.iter_mut().find(|entry| {
+            entry
+                .split_once(':')
+                .is_some_and(|(entry_name, _)| entry_name.trim() == name)
+        }) {
+            Some(entry) => *entry = new_entry,
+            None => entries.push(new_entry),
+        }
+    }
+
+    let mut new_content = entries.join("\n");
+    new_content.push('\n');
+
+    new_content
+}
+
+/* Picks a crtc for each head, a group of monitors showing the same picture. A crtc is only picked for a head if it can drive all of its outputs, going by
+both the outputs' list of crtcs and the crtc's list of possible outputs, which is what hybrid
+graphics and GPUs with limited crtcs get wrong the most. Heads keep their current crtc when
+possible, the rest is a bipartite matching between heads and crtcs. The crtcs of the `off`
+monitors are up for grabs too, since they're being turned off anyway. */
+pub fn assign_crtcs(
+    xstack: &XCBStack,
+    heads: &[Vec<&Monitor>],
+    off: &[&Monitor],
+) -> Result<Vec<randr::Crtc>> {
+    let screen_resources = xcb_make_request!(
+        xstack.conn,
+        &randr::GetScreenResourcesCurrent {
+            window: xstack.root_window,
+        }
+    );
+
+    let head_outputs = heads
+        .iter()
+        .flatten()
+        .chain(off)
+        .map(|mon| mon.output)
+        .collect::<Vec<randr::Output>>();
+
+    // Crtcs driving only monitors outside of the layout are left alone, along with their outputs
+    let mut usable_crtcs = Vec::<(randr::Crtc, Vec<randr::Output>)>::new();
+    for crtc in screen_resources.crtcs() {
+        let crtc_info = xcb_make_request!(
+            xstack.conn,
+            &randr::GetCrtcInfo {
+                crtc: *crtc,
+                config_timestamp: xcb::x::CURRENT_TIME,
             }
         );
 
-        return Ok(true);
+        if !crtc_info.outputs().is_empty()
+            && !crtc_info
+                .outputs()
+                .iter()
+                .any(|output| head_outputs.contains(output))
+        {
+            continue;
+        }
+
+        usable_crtcs.push((*crtc, crtc_info.possible().to_vec()));
     }
+
+    match_crtcs(heads, &usable_crtcs)
 }
 
-#[derive(Debug)]
+/* Picks a crtc for each head out of `usable_crtcs` (with the outputs each of them can drive),
+in the same order as `heads`. Heads keep their current crtc whenever they can. */
+fn match_crtcs(
+    heads: &[Vec<&Monitor>],
+    usable_crtcs: &[(randr::Crtc, Vec<randr::Output>)],
+) -> Result<Vec<randr::Crtc>> {
+    // The crtcs each head can use, its current one first
+    let candidates = heads
+        .iter()
+        .map(|head| {
+            let mut head_candidates = (0..usable_crtcs.len())
+                .filter(|&i| {
+                    let (crtc, possible) = &usable_crtcs[i];
+                    head.iter()
+                        .all(|mon| mon.crtcs.contains(crtc) && possible.contains(&mon.output))
+                })
+                .collect::<Vec<usize>>();
+
+            let cur_crtc = head.iter().find_map(|mon| mon.crtc);
+            head_candidates.sort_by_key(|&i| Some(usable_crtcs[i].0) != cur_crtc);
+
+            head_candidates
+        })
+        .collect::<Vec<Vec<usize>>>();
+
+    // Heads that are already on a crtc go first so they get to keep it
+    let mut order = (0..heads.len()).collect::<Vec<usize>>();
+    order.sort_by_key(|&head| heads[head].iter().all(|mon| mon.crtc.is_none()));
+
+    let mut crtc_owners = vec![None; usable_crtcs.len()];
+    for head in order {
+        let mut visited = vec![false; usable_crtcs.len()];
+        if assign_crtc_to_head(head, &candidates, &mut crtc_owners, &mut visited) {
+            continue;
+        }
+
+        let head_names = heads[head]
+            .iter()
+            .map(|mon| mon.name.as_str())
+            .collect::<Vec<&str>>()
+            .join(", ");
+
+        return Err(Error::Resources(match candidates[head].is_empty() {
+            true => format_args!(
+                "None of the {} crtc(s) not in use by other monitors can drive {}",
+                usable_crtcs.len(),
+                head_names
+            )
+            .to_string(),
+            false => format_args!(
+                "Not enough crtcs to drive {} along with the other monitors of the layout. Can your GPU handle this many monitors?",
+                head_names
+            )
+            .to_string(),
+        }));
+    }
+
+    let mut assigned = vec![randr::Crtc::none(); heads.len()];
+    for (i, owner) in crtc_owners.iter().enumerate() {
+        if let Some(head) = owner {
+            assigned[*head] = usable_crtcs[i].0;
+        }
+    }
+
+    Ok(assigned)
+}
+
+/* Gives `head` one of its candidate crtcs, moving the heads in the way to other crtcs if needed
+(an augmenting path, as in Kuhn's matching algorithm) */
+fn assign_crtc_to_head(
+    head: usize,
+    candidates: &[Vec<usize>],
+    crtc_owners: &mut [Option<usize>],
+    visited: &mut [bool],
+) -> bool {
+    for &crtc in &candidates[head] {
+        if visited[crtc] {
+            continue;
+        }
+        visited[crtc] = true;
+
+        let free = match crtc_owners[crtc] {
+            Some(owner) => assign_crtc_to_head(owner, candidates, crtc_owners, visited),
+            None => true,
+        };
+
+        if free {
+            crtc_owners[crtc] = Some(head);
+            return true;
+        }
+    }
+
+    false
+}
+
+/* Applies the crtc configs and screen size as a whole: if anything fails, every crtc and the
+screen are put back the way they were before. The server is grabbed for the duration so that
+no client gets to see (or mess with) the intermediate states.
+
+Like xrandr does it, the screen is grown before enabling any crtc that wouldn't fit in the
+current one and only shrunk once every crtc fits in the new size. Crtcs we don't touch keep
+running, so the screen is also kept large enough for them.
+
+Returns whether anything changed. */
+pub fn apply_layout(
+    xstack: &XCBStack,
+    crtc_configs: &[CrtcConfig],
+    screen: &ScreenSize,
+) -> Result<bool> {
+    let old_screen = ScreenSize::get(xstack)?;
+
+    let screen_resources = xcb_make_request!(
+        xstack.conn,
+        &randr::GetScreenResourcesCurrent {
+            window: xstack.root_window,
+        }
+    );
+
+    let mut all_crtc_configs = Vec::<CrtcConfig>::with_capacity(screen_resources.crtcs().len());
+    for crtc in screen_resources.crtcs() {
+        all_crtc_configs.push(CrtcConfig::get(xstack, *crtc)?);
+    }
+
+    /* Monitors moving to another crtc have to be taken off the old one, unless it's part of the
+    layout anyway */
+    let mut crtc_configs = crtc_configs.to_vec();
+    for old_conf in &all_crtc_configs {
+        if !old_conf.is_disabled()
+            && !crtc_configs.iter().any(|conf| conf.crtc == old_conf.crtc)
+            && crtc_configs
+                .iter()
+                .any(|conf| conf.outputs.iter().any(|o| old_conf.outputs.contains(o)))
+        {
+            crtc_configs.push(CrtcConfig::disabled(old_conf.crtc));
+        }
+    }
+
+    let mut screen = screen.clone();
+    for other_crtc_config in all_crtc_configs.iter().filter(|old_conf| {
+        !old_conf.is_disabled() && !crtc_configs.iter().any(|conf| conf.crtc == old_conf.crtc)
+    }) {
+        screen.width = std::cmp::max(
+            screen.width,
+            (other_crtc_config.x.max(0) as u16).saturating_add(other_crtc_config.w),
+        );
+        screen.height = std::cmp::max(
+            screen.height,
+            (other_crtc_config.y.max(0) as u16).saturating_add(other_crtc_config.h),
+        );
+    }
+
+    // In the same order as crtc_configs
+    let old_crtc_configs = crtc_configs
+        .iter()
+        .map(|conf| {
+            all_crtc_configs
+                .iter()
+                .find(|old_conf| old_conf.crtc == conf.crtc)
+                .cloned()
+                .unwrap_or(CrtcConfig::disabled(conf.crtc))
+        })
+        .collect::<Vec<CrtcConfig>>();
+
+    let size_range = xcb_make_request!(
+        xstack.conn,
+        &randr::GetScreenSizeRange {
+            window: xstack.root_window,
+        }
+    );
+
+    /* Like xrandr does, layouts smaller than the smallest screen the X server supports (e.g. with
+    every monitor off) get that one instead, at the same dpi if there's one to keep */
+    if screen.width < size_range.min_width() || screen.height < size_range.min_height() {
+        let dpi = match (screen.width, screen.mm_width) {
+            (0, _) | (_, 0) => 96.0,
+            (width, mm_width) => width as f64 * 25.4 / mm_width as f64,
+        };
+
+        screen = ScreenSize::from_dpi(
+            std::cmp::max(screen.width, size_range.min_width()),
+            std::cmp::max(screen.height, size_range.min_height()),
+            dpi,
+        );
+    }
+
+    // The size in millimeters counts too, as it's how the dpi policy of a setup gets applied
+    if crtc_configs.is_empty() && screen == old_screen {
+        return Ok(false);
+    }
+
+    if screen.width > size_range.max_width() || screen.height > size_range.max_height() {
+        return Err(Error::Resources(
+            format_args!(
+                "The layout needs a {}x{} screen but the X server only supports sizes up to {}x{}",
+                screen.width,
+                screen.height,
+                size_range.max_width(),
+                size_range.max_height()
+            )
+            .to_string(),
+        ));
+    }
+
+    // Large enough for both the old and the new layout
+    let grown_screen = ScreenSize {
+        width: std::cmp::max(old_screen.width, screen.width),
+        height: std::cmp::max(old_screen.height, screen.height),
+        mm_width: std::cmp::max(old_screen.mm_width, screen.mm_width),
+        mm_height: std::cmp::max(old_screen.mm_height, screen.mm_height),
+    };
+
+    xstack.conn.send_request(&xcb::x::GrabServer {});
+
+    let result = (|| -> Result<()> {
+        /* Crtcs that are only moving stay on, but those changing mode or outputs are turned
+        off first, as are those that would end up outside of the screen once it's shrunk */
+        for (old_crtc_config, crtc_config) in old_crtc_configs.iter().zip(&crtc_configs) {
+            if !old_crtc_config.is_disabled()
+                && (old_crtc_config.mode != crtc_config.mode
+                    || old_crtc_config.outputs != crtc_config.outputs
+                    || !old_crtc_config.fits_in(&screen))
+            {
+                CrtcConfig::disabled(old_crtc_config.crtc).set(xstack)?;
+            }
+        }
+
+        if grown_screen != old_screen {
+            grown_screen.set(xstack)?;
+        }
+
+        for crtc_config in &crtc_configs {
+            crtc_config.set(xstack)?;
+        }
+
+        if screen != grown_screen {
+            screen.set(xstack)?;
+        }
+
+        Ok(())
+    })();
+
+    if let Err(err) = result {
+        // Best effort, there's not much left to do if rolling back fails too
+        for crtc_config in &crtc_configs {
+            let _ = CrtcConfig::disabled(crtc_config.crtc).set(xstack);
+        }
+
+        let _ = old_screen.set(xstack);
+
+        for old_crtc_config in old_crtc_configs.iter().filter(|conf| !conf.is_disabled()) {
+            let _ = old_crtc_config.set(xstack);
+        }
+
+        xstack.conn.send_request(&xcb::x::UngrabServer {});
+        xstack.conn.flush()?;
+
+        return Err(match err {
+            Error::Resources(msg) => Error::Resources(
+                format_args!("{} (the previous layout was restored)", msg).to_string(),
+            ),
+            Error::Other(msg) => {
+                Error::Other(format_args!("{} (the previous layout was restored)", msg).to_string())
+            }
+            err => err,
+        });
+    }
+
+    xstack.conn.send_request(&xcb::x::UngrabServer {});
+    xstack.conn.flush()?;
+
+    Ok(true)
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct MonitorConfig {
     pub id: u32,
     pub x: i16,
     pub y: i16,
+    /* Along with the reflection bits */
     pub rot: randr::Rotation,
+    /* Size of the mode to use instead of the best one */
+    pub mode: Option<(u16, u16)>,
+    /* Refresh rate (Hz) to pick the mode by, among those of the right size */
+    pub rate: Option<f64>,
+    /* How many screen pixels each of the mode's pixels covers, like 'xrandr --scale' */
+    pub scale: f64,
+    /* Made the randr primary output once applied */
+    pub primary: bool,
+    /* Connected but turned off, the monitor is left out of the screen */
+    pub off: bool,
+}
+
+impl MonitorConfig {
+    /* The area covered on the screen when showing `mode`, once rotated and scaled */
+    pub fn extent(&self, mode: &MonitorMode) -> (u16, u16) {
+        let w = ((mode.width as f64) * self.scale)
+            .round()
+            .min(u16::MAX as f64) as u16;
+        let h = ((mode.height as f64) * self.scale)
+            .round()
+            .min(u16::MAX as f64) as u16;
+
+        match self
+            .rot
+            .intersects(randr::Rotation::ROTATE_90 | randr::Rotation::ROTATE_270)
+        {
+            true => (h, w),
+            false => (w, h),
+        }
+    }
+
+    pub fn new(id: u32, x: i16, y: i16, rot: randr::Rotation) -> MonitorConfig {
+        MonitorConfig {
+            id,
+            x,
+            y,
+            rot,
+            mode: None,
+            rate: None,
+            scale: 1.0,
+            primary: false,
+            off: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resources(list: &[(&str, &str)]) -> Vec<(String, String)> {
+        list.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn xresources_keep_continued_values() {
+        let content = "*background:\t#1d1f21\nURxvt.keysym.C-1: \\\n  command:foo\nXft.dpi:\t96\n";
+
+        assert_eq!(
+            split_xresources(content),
+            vec![
+                "*background:\t#1d1f21",
+                "URxvt.keysym.C-1: \\\n  command:foo",
+                "Xft.dpi:\t96"
+            ]
+        );
+        assert_eq!(
+            merge_xresources_content(content, &resources(&[("Xft.dpi", "192")])),
+            "*background:\t#1d1f21\nURxvt.keysym.C-1: \\\n  command:foo\nXft.dpi:\t192\n"
+        );
+    }
+
+    #[test]
+    fn xresources_replace_continued_values() {
+        let content = "Xft.dpi: \\\n96\nXcursor.size:\t24\n";
+
+        assert_eq!(
+            merge_xresources_content(
+                content,
+                &resources(&[("Xft.dpi", "192"), ("Xft.hinting", "1")])
+            ),
+            "Xft.dpi:\t192\nXcursor.size:\t24\nXft.hinting:\t1\n"
+        );
+    }
+
+    #[test]
+    fn xresources_escaped_backslash_ends_the_value() {
+        let content = "a.path:\tC:\\\\\nb.value:\t1\n";
+
+        assert_eq!(
+            split_xresources(content),
+            vec!["a.path:\tC:\\\\", "b.value:\t1"]
+        );
+    }
+
+    fn crtc(n: u32) -> randr::Crtc {
+        unsafe { xcb::XidNew::new(n) }
+    }
+
+    fn output(n: u32) -> randr::Output {
+        unsafe { xcb::XidNew::new(n) }
+    }
+
+    /* A monitor on output `n`, driven by `current` and able to use the `crtcs` given */
+    fn mon(n: u32, current: Option<u32>, crtcs: &[u32]) -> Monitor {
+        Monitor {
+            id: n,
+            name: format_args!("OUT-{}", n).to_string(),
+            edid: None,
+            edid_error: None,
+            crtc_config: None,
+            output: output(n),
+            mode_best: randr::Mode::none(),
+            crtc: current.map(crtc),
+            crtcs: crtcs.iter().copied().map(crtc).collect(),
+            clones: Vec::new(),
+            modes: Vec::new(),
+            w: 1920,
+            h: 1080,
+            w_mm: 0,
+            h_mm: 0,
+        }
+    }
+
+    /* Crtcs that can drive every output up to 9 */
+    fn usable(crtcs: &[u32]) -> Vec<(randr::Crtc, Vec<randr::Output>)> {
+        crtcs
+            .iter()
+            .map(|&n| (crtc(n), (1..10).map(output).collect()))
+            .collect()
+    }
+
+    #[test]
+    fn crtcs_stay_with_their_heads() {
+        let a = mon(1, Some(102), &[101, 102]);
+        let b = mon(2, Some(101), &[101, 102]);
+
+        assert_eq!(
+            match_crtcs(&[vec![&a], vec![&b]], &usable(&[101, 102])).unwrap(),
+            vec![crtc(102), crtc(101)]
+        );
+    }
+
+    #[test]
+    fn crtcs_get_moved_to_make_room() {
+        /* a is on the only crtc b can use, so it has to move over to make room for b */
+        let a = mon(1, Some(101), &[101, 102]);
+        let b = mon(2, None, &[101]);
+
+        assert_eq!(
+            match_crtcs(&[vec![&a], vec![&b]], &usable(&[101, 102])).unwrap(),
+            vec![crtc(102), crtc(101)]
+        );
+    }
+
+    #[test]
+    fn crtcs_have_to_drive_every_output_of_a_head() {
+        let a = mon(1, None, &[101, 102]);
+        let b = mon(2, None, &[102]);
+        let c = mon(3, None, &[101, 102]);
+
+        /* 101 can't drive output 2, so the mirrored a and b can only share 102 */
+        let usable_crtcs = vec![
+            (crtc(101), vec![output(1), output(3)]),
+            (crtc(102), vec![output(1), output(2), output(3)]),
+        ];
+
+        assert_eq!(
+            match_crtcs(&[vec![&c], vec![&a, &b]], &usable_crtcs).unwrap(),
+            vec![crtc(101), crtc(102)]
+        );
+    }
+
+    #[test]
+    fn not_enough_crtcs() {
+        let a = mon(1, Some(101), &[101, 102]);
+        let b = mon(2, None, &[101, 102]);
+        let c = mon(3, None, &[101, 102]);
+
+        match match_crtcs(&[vec![&a], vec![&b], vec![&c]], &usable(&[101, 102])) {
+            Err(Error::Resources(msg)) => assert_eq!(
+                msg,
+                "Not enough crtcs to drive OUT-3 along with the other monitors of the layout. Can your GPU handle this many monitors?"
+            ),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+
+        match match_crtcs(&[vec![&c]], &usable(&[103])) {
+            Err(Error::Resources(msg)) => assert_eq!(
+                msg,
+                "None of the 1 crtc(s) not in use by other monitors can drive OUT-3"
+            ),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
 }
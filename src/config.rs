@@ -0,0 +1,1553 @@
+// Copyright Olaru Alexandru
+// Licensed under the MIT license
+
+/* What the config dir holds and how it's read: setups and global settings, parsed from the lines
+lexer.rs splits the files into */
+
+use crate::{amc, lexer, POLL_INTERVAL};
+use core::time;
+use std::path::Path;
+use xcb::randr;
+
+#[derive(Debug)]
+pub struct MonitorSetup {
+    /* From its '[setup <name>]' section, or the config file's name without the extension */
+    pub name: String,
+    /* Setups with a higher priority win over those covering more monitors */
+    pub priority: i32,
+    pub configs: Vec<amc::MonitorConfig>,
+    pub dpi: amc::DpiPolicy,
+    /* X resources (name, value) merged into RESOURCE_MANAGER once the setup is applied */
+    pub xresources: Vec<(String, String)>,
+    /* Limits the setup to a display and/or screen, it's considered for any of them if None */
+    pub display: Option<String>,
+    pub screen: Option<i32>,
+    /* Limits the setup to a machine, by hostname and/or by the ids of its connected built-in
+    panels (sorted), it's considered on any of them if None */
+    pub host: Option<String>,
+    pub panels: Option<Vec<u32>>,
+    /* Every alias (name, monitor id) its monitors could be written with, the global ones
+    included */
+    pub aliases: Vec<(String, u32)>,
+    /* The setup it inherits from, with 'extends = <setup>' */
+    pub extends: Option<String>,
+}
+
+impl MonitorSetup {
+    /* What the setup isn't meant for, if it's limited to other displays, screens or machines
+    than the one `mons` are connected to */
+    pub fn out_of_scope(
+        &self,
+        xstack: &amc::XCBStack,
+        host: Option<&str>,
+        mons: &[amc::Monitor],
+    ) -> Option<String> {
+        if self
+            .display
+            .as_ref()
+            .is_some_and(|display| *display != xstack.display)
+            || self
+                .screen
+                .is_some_and(|screen| screen != xstack.screen_num)
+        {
+            return Some(format_args!("display {}", xstack.name()).to_string());
+        }
+
+        if self.host.as_deref().is_some_and(|name| Some(name) != host) {
+            return Some(format_args!("host '{}'", host.unwrap_or("unknown")).to_string());
+        }
+
+        let mut panels = mons
+            .iter()
+            .filter(|mon| mon.is_internal())
+            .map(|mon| mon.id)
+            .collect::<Vec<u32>>();
+        panels.sort();
+
+        if self.panels.as_ref().is_some_and(|ids| *ids != panels) {
+            return Some(match panels.is_empty() {
+                true => "a machine without a connected built-in panel".to_string(),
+                false => format_args!(
+                    "a machine with the built-in panels {}",
+                    panels
+                        .iter()
+                        .map(|id| format_args!("{:x}", id).to_string())
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                )
+                .to_string(),
+            });
+        }
+
+        None
+    }
+}
+
+/* What to do with the connected monitors when neither a setup nor a remembered layout matches */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AmcFallback {
+    /* Everything at 0x0 in its best mode */
+    Mirror,
+    /* Next to each other from left to right, in their current order */
+    Extend,
+    /* Leave the monitors as they are */
+    Keep,
+}
+
+/* Settings for amc as a whole, from the [global] sections of the config files */
+#[derive(Debug, Clone)]
+pub struct AmcGlobals {
+    pub fallback: AmcFallback,
+    pub poll_interval: time::Duration,
+    /* Shell command run whenever a layout gets applied */
+    pub hook: Option<String>,
+    /* Same as --enforce */
+    pub enforce: bool,
+    /* Names for monitor ids (name, monitor id) usable in every setup */
+    pub aliases: Vec<(String, u32)>,
+}
+
+impl Default for AmcGlobals {
+    fn default() -> AmcGlobals {
+        AmcGlobals {
+            fallback: AmcFallback::Mirror,
+            poll_interval: POLL_INTERVAL,
+            hook: None,
+            enforce: false,
+            aliases: Vec::new(),
+        }
+    }
+}
+
+/* Everything read from the config dir */
+#[derive(Debug, Default)]
+pub struct AmcConfig {
+    pub globals: AmcGlobals,
+    pub setups: Vec<MonitorSetup>,
+}
+
+/* Splits a '<key> = <value>' line, the value being every token after the '=' */
+fn amc_split_setting<'a, 'b>(
+    line: &'b lexer::Line<'a>,
+) -> amc::Result<(lexer::Token<'a>, &'b [lexer::Token<'a>])> {
+    let key = line.tokens[0];
+    if !key.is(lexer::TokenKind::Word) {
+        return Err(line.error_at(
+            &key,
+            &format_args!("Unexpected {}, expected a setting", key.quoted()).to_string(),
+        ));
+    }
+
+    let expected_equals = format_args!("Expected '=' after {}", key.quoted()).to_string();
+    match line.tokens.get(1) {
+        Some(token) if token.is(lexer::TokenKind::Equals) => (),
+        Some(token) => return Err(line.error_at(token, &expected_equals)),
+        None => return Err(line.error_at_end(&expected_equals)),
+    }
+
+    match &line.tokens[2..] {
+        [] => {
+            Err(line
+                .error_at_end(&format_args!("Missing value after {} =", key.quoted()).to_string()))
+        }
+        value => Ok((key, value)),
+    }
+}
+
+/* The value of a setting that takes a single word */
+fn amc_single_value<'a>(
+    line: &lexer::Line<'a>,
+    key: &lexer::Token,
+    value: &[lexer::Token<'a>],
+) -> amc::Result<lexer::Token<'a>> {
+    match value {
+        [token] if token.is(lexer::TokenKind::Word) => Ok(*token),
+        [token, ..] if !token.is(lexer::TokenKind::Word) => Err(line.error_at(
+            token,
+            &format_args!("Unexpected {}", token.quoted()).to_string(),
+        )),
+        [_, extra @ ..] if !extra.is_empty() => Err(line.error_between(
+            &extra[0],
+            extra.last().unwrap(),
+            &format_args!("{} only takes a single value", key.quoted()).to_string(),
+        )),
+        _ => Err(line.error_at_end("Missing value")),
+    }
+}
+
+/* The values of a monitor line, split on commas. Each part comes with the token it starts at,
+or ends up after for empty ones, for errors to point at. */
+fn amc_split_values<'a, 'b>(
+    value: &'b [lexer::Token<'a>],
+) -> Vec<(lexer::Token<'a>, &'b [lexer::Token<'a>])> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+
+    for (i, token) in value.iter().enumerate() {
+        if token.is(lexer::TokenKind::Comma) {
+            parts.push((value[start.min(i)], &value[start..i]));
+            start = i + 1;
+        }
+    }
+    parts.push((value[start.min(value.len() - 1)], &value[start..]));
+
+    parts
+}
+
+/* Parses the lines of a setup, adding whatever is wrong with them to
+`errors`. Each line is either a setting, an alias, an X resource or a monitor:
+    <monitor id or alias> = <x>x<y>, <rotation degrees>[, <option>...]
+    <monitor id or alias> = off
+Monitors can be written with the given `aliases` or those of the setup itself, which can be
+used before they're defined. A setup extending `base` starts off with its settings, monitors, X
+resources and aliases, and overrides the ones it gives itself. */
+pub fn amc_parse_setup(
+    name: &str,
+    lines: &[lexer::Line],
+    aliases: &[(String, u32)],
+    base: Option<&MonitorSetup>,
+    errors: &mut Vec<amc::Error>,
+) -> MonitorSetup {
+    let mut mon_setup = MonitorSetup {
+        name: name.to_string(),
+        priority: base.map_or(0, |base| base.priority),
+        configs: Vec::new(),
+        dpi: base.map_or(amc::DpiPolicy::Primary, |base| base.dpi),
+        xresources: base.map_or(Vec::new(), |base| base.xresources.clone()),
+        display: base.and_then(|base| base.display.clone()),
+        screen: base.and_then(|base| base.screen),
+        host: base.and_then(|base| base.host.clone()),
+        panels: base.and_then(|base| base.panels.clone()),
+        aliases: base.map_or(Vec::new(), |base| base.aliases.clone()),
+        extends: None,
+    };
+    mon_setup.aliases.extend(aliases.iter().cloned());
+
+    for line in lines.iter().filter(|line| amc_is_alias(line)) {
+        match amc_parse_alias(line) {
+            Ok(alias) => mon_setup.aliases.push(alias),
+            Err(err) => errors.push(err),
+        }
+    }
+
+    // Lines are independent from each other, so a mistake doesn't hide those on later lines
+    for line in lines
+        .iter()
+        .filter(|line| !line.is_empty() && !amc_is_alias(line))
+    {
+        if let Err(err) = amc_parse_setup_line(&mut mon_setup, line) {
+            errors.push(err);
+        }
+    }
+
+    /* The monitors of `base` come first, replaced by those the setup has too, which also take
+    over being primary */
+    if let Some(base) = base {
+        let own = std::mem::take(&mut mon_setup.configs);
+        let own_primary = own.iter().any(|conf| conf.primary);
+
+        mon_setup.configs = base
+            .configs
+            .iter()
+            .map(|conf| match own.iter().find(|own| own.id == conf.id) {
+                Some(own) => own.clone(),
+                None => amc::MonitorConfig {
+                    primary: conf.primary && !own_primary,
+                    ..conf.clone()
+                },
+            })
+            .chain(
+                own.iter()
+                    .filter(|own| !base.configs.iter().any(|conf| conf.id == own.id))
+                    .cloned(),
+            )
+            .collect();
+    }
+
+    mon_setup
+}
+
+/* The 'extends = <setup>' line of a setup and the name it gives, if it has a well formed one.
+Setups are parsed after the one they extend, so this is looked for before parsing them. */
+fn amc_find_extends<'a, 'b>(
+    lines: &'b [lexer::Line<'a>],
+) -> Option<(&'b lexer::Line<'a>, lexer::Token<'a>)> {
+    lines.iter().find_map(|line| match line.tokens.as_slice() {
+        [key, equals, name]
+            if key.text == "extends"
+                && equals.is(lexer::TokenKind::Equals)
+                && name.is(lexer::TokenKind::Word) =>
+        {
+            Some((line, *name))
+        }
+        _ => None,
+    })
+}
+
+/* Settings that can't be alias names, as they'd be taken for the setting */
+const AMC_SETTINGS: [&str; 9] = [
+    "alias", "dpi", "display", "screen", "host", "panels", "priority", "extends", "version",
+];
+
+fn amc_is_alias(line: &lexer::Line) -> bool {
+    line.tokens
+        .first()
+        .is_some_and(|token| token.text == "alias")
+        && line
+            .tokens
+            .get(1)
+            .is_some_and(|token| !token.is(lexer::TokenKind::Equals))
+}
+
+/* An 'alias <name> = <monitor id>' line */
+fn amc_parse_alias(line: &lexer::Line) -> amc::Result<(String, u32)> {
+    let name = line.tokens[1];
+    if !name.is(lexer::TokenKind::Word) {
+        return Err(line.error_at(
+            &name,
+            &format_args!("Unexpected {}, expected an alias name", name.quoted()).to_string(),
+        ));
+    }
+
+    if u32::from_str_radix(name.text, 16).is_ok() {
+        return Err(line.error_at(
+            &name,
+            "Invalid alias name (hex numbers would be taken for monitor ids)",
+        ));
+    }
+    if name.text.contains(':') {
+        return Err(line.error_at(&name, "Invalid alias name (names can't have ':' in them)"));
+    }
+    if AMC_SETTINGS.contains(&name.text) {
+        return Err(line.error_at(
+            &name,
+            &format_args!(
+                "Invalid alias name ({} would be taken for the setting)",
+                name.quoted()
+            )
+            .to_string(),
+        ));
+    }
+
+    let expected_equals = format_args!("Expected '=' after {}", name.quoted()).to_string();
+    match line.tokens.get(2) {
+        Some(token) if token.is(lexer::TokenKind::Equals) => (),
+        Some(token) => return Err(line.error_at(token, &expected_equals)),
+        None => return Err(line.error_at_end(&expected_equals)),
+    }
+
+    let id = amc_single_value(line, &name, &line.tokens[3..])?;
+    match u32::from_str_radix(id.text, 16) {
+        Ok(x) => Ok((name.text.to_string(), x)),
+        Err(_) => Err(line.error_at(
+            &id,
+            "Invalid monitor id (monitor ids are the hex numbers shown by 'amc -p')",
+        )),
+    }
+}
+
+fn amc_parse_setup_line(mon_setup: &mut MonitorSetup, line: &lexer::Line) -> amc::Result<()> {
+    /* X resources are written the way xrdb takes them ('<name>: <value>'), and the only
+    lines with a ':' before any '='. Their values can be anything (e.g. colors like '#1d1f21')
+    so they're taken as written, comments included. */
+    if let Some((name, _)) = line
+        .content()
+        .split_once(':')
+        .filter(|(name, _)| !name.contains('='))
+    {
+        let value = &line.text[name.len() + 1..];
+        let name = name.trim();
+
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(line.error_in(
+                name,
+                "Invalid X resource name (names can't be empty or have whitespace in them)",
+            ));
+        }
+
+        mon_setup
+            .xresources
+            .push((name.to_string(), value.trim().to_string()));
+        return Ok(());
+    }
+
+    let (key, value) = amc_split_setting(line)?;
+
+    match key.text {
+        "dpi" => {
+            let dpi = amc_single_value(line, &key, value)?;
+
+            mon_setup.dpi = match dpi.text {
+                "primary" => amc::DpiPolicy::Primary,
+                "average" => amc::DpiPolicy::Average,
+                text => match text.parse::<f64>() {
+                    Ok(x) if x > 0.0 && x.is_finite() => amc::DpiPolicy::Fixed(x),
+                    _ => return Err(line.error_at(
+                        &dpi,
+                        "Invalid dpi (dpi can only be 'primary', 'average' or a positive number)",
+                    )),
+                },
+            };
+        }
+        "display" => {
+            // A screen number in the name (e.g. ':0.1') scopes the setup to that screen too
+            let (display, screen) =
+                amc::split_display_name(amc_single_value(line, &key, value)?.text);
+
+            mon_setup.display = Some(display);
+            if screen.is_some() {
+                mon_setup.screen = screen;
+            }
+        }
+        "screen" => {
+            let screen = amc_single_value(line, &key, value)?;
+
+            mon_setup.screen = match screen.text.parse::<i32>() {
+                Ok(x) if x >= 0 => Some(x),
+                _ => {
+                    return Err(
+                        line.error_at(&screen, "Invalid screen (screen must be a screen number)")
+                    )
+                }
+            };
+        }
+        "host" => mon_setup.host = Some(amc_single_value(line, &key, value)?.text.to_string()),
+        "panels" => {
+            let mut panels = Vec::<u32>::new();
+
+            for (at, part) in amc_split_values(value) {
+                let panel = match part {
+                    [panel] if panel.is(lexer::TokenKind::Word) => panel,
+                    [] => return Err(line.error_at(&at, "Missing panel")),
+                    [first, ..] => {
+                        return Err(line.error_between(
+                            first,
+                            part.last().unwrap(),
+                            "Invalid panel (panels are separated by ',')",
+                        ))
+                    }
+                };
+
+                match amc_monitor_id(mon_setup, panel.text) {
+                    Some(id) => panels.push(id),
+                    None => return Err(line.error_at(
+                        panel,
+                        "Unknown alias or invalid monitor id (monitor ids are the hex numbers shown by 'amc -p')",
+                    )),
+                }
+            }
+
+            panels.sort();
+            mon_setup.panels = Some(panels);
+        }
+        "priority" => {
+            let priority = amc_single_value(line, &key, value)?;
+
+            mon_setup.priority = match priority.text.parse::<i32>() {
+                Ok(x) => x,
+                Err(_) => {
+                    return Err(line.error_at(
+                        &priority,
+                        "Invalid priority (priority must be a whole number)",
+                    ))
+                }
+            };
+        }
+        // Resolved when reading the config dir, see amc_read_config_from_dir
+        "extends" => {
+            let base = amc_single_value(line, &key, value)?;
+
+            if mon_setup.extends.is_some() {
+                return Err(line.error(
+                    "A setup can only extend a single setup, make that one extend the other",
+                ));
+            }
+            if !amc_valid_setup_name(base.text) {
+                return Err(
+                    line.error_at(&base, "Invalid setup name (names can't have '/' in them)")
+                );
+            }
+
+            mon_setup.extends = Some(base.text.to_string());
+        }
+        _ => mon_setup
+            .configs
+            .push(amc_parse_monitor(mon_setup, line, &key, value)?),
+    }
+
+    Ok(())
+}
+
+/* The monitor id `text` stands for in a setup, either an alias or the id itself */
+fn amc_monitor_id(mon_setup: &MonitorSetup, text: &str) -> Option<u32> {
+    // Later aliases win over earlier ones, so a setup can redefine a global one
+    match mon_setup
+        .aliases
+        .iter()
+        .rev()
+        .find(|(name, _)| name == text)
+    {
+        Some(&(_, id)) => Some(id),
+        None => u32::from_str_radix(text, 16).ok(),
+    }
+}
+
+fn amc_parse_monitor(
+    mon_setup: &MonitorSetup,
+    line: &lexer::Line,
+    key: &lexer::Token,
+    value: &[lexer::Token],
+) -> amc::Result<amc::MonitorConfig> {
+    let mon_id = match amc_monitor_id(mon_setup, key.text) {
+        Some(x) => x,
+        None => return Err(line.error_at(
+            key,
+            "Unknown setting or alias, or invalid monitor id (monitor ids are the hex numbers shown by 'amc -p')",
+        )),
+    };
+
+    if let [token] = value {
+        if token.text == "off" {
+            return Ok(amc::MonitorConfig {
+                off: true,
+                ..amc::MonitorConfig::new(mon_id, 0, 0, randr::Rotation::ROTATE_0)
+            });
+        }
+    }
+
+    // The position and rotation come first, followed by the options
+    let parts = amc_split_values(value);
+    for (i, (at, part)) in parts.iter().enumerate() {
+        let what = match i {
+            0 => "monitor position",
+            1 => "monitor rotation",
+            _ => "monitor option",
+        };
+
+        match part {
+            [] if i + 1 == parts.len() => {
+                return Err(line.error_at_end(&format_args!("Missing {}", what).to_string()))
+            }
+            [] => return Err(line.error_at(at, &format_args!("Missing {}", what).to_string())),
+            [word] if word.is(lexer::TokenKind::Word) => (),
+            [key, equals, value]
+                if i > 1
+                    && key.is(lexer::TokenKind::Word)
+                    && equals.is(lexer::TokenKind::Equals)
+                    && value.is(lexer::TokenKind::Word) => {}
+            [first, .., last] if first.is(lexer::TokenKind::Word) && i < 2 => {
+                return Err(line.error_between(
+                    &part[1],
+                    last,
+                    &format_args!(
+                        "Unexpected {} (values are separated by ',')",
+                        part[1].quoted()
+                    )
+                    .to_string(),
+                ))
+            }
+            [first, ..] if i < 2 => {
+                return Err(line.error_between(
+                    first,
+                    part.last().unwrap(),
+                    &format_args!("Invalid {}", what).to_string(),
+                ))
+            }
+            [first, ..] => return Err(line.error_between(
+                first,
+                part.last().unwrap(),
+                "Invalid monitor option (options are written <name>=<value>, or just 'primary')",
+            )),
+        }
+    }
+
+    let position = parts[0].1[0];
+    let (x, y) = match position.text.split_once('x') {
+        Some((x, y)) => match (x.parse::<i16>(), y.parse::<i16>()) {
+            (Ok(x), Ok(y)) => (x, y),
+            _ => {
+                return Err(line.error_at(
+                    &position,
+                    &format_args!(
+                        "Invalid monitor position (coordinates must be between {} and {})",
+                        i16::MIN,
+                        i16::MAX
+                    )
+                    .to_string(),
+                ))
+            }
+        },
+        None => {
+            return Err(line.error_at(
+                &position,
+                "Invalid monitor position (positions are written <x>x<y>, e.g. '1920x0')",
+            ))
+        }
+    };
+
+    let rotation = match parts.get(1) {
+        Some((_, part)) => part[0],
+        None => return Err(line.error_at_end("Missing monitor rotation")),
+    };
+    let rot = match rotation.text.parse::<u16>() {
+        Ok(0) => randr::Rotation::ROTATE_0,
+        Ok(90) => randr::Rotation::ROTATE_90,
+        Ok(180) => randr::Rotation::ROTATE_180,
+        Ok(270) => randr::Rotation::ROTATE_270,
+        _ => return Err(line.error_at(
+            &rotation,
+            "Invalid monitor rotation (rotation can only have the following values: 0, 90, 180, 270)",
+        )),
+    };
+
+    let mut conf = amc::MonitorConfig::new(mon_id, x, y, rot);
+
+    for (_, option) in parts.iter().skip(2) {
+        let (key, value) = match option {
+            [key] => (key, None),
+            [key, _, value] => (key, Some(value)),
+            _ => unreachable!(),
+        };
+
+        let invalid = |msg: &str| line.error_at(value.unwrap_or(key), msg);
+
+        match (key.text, value.map(|value| value.text)) {
+            ("primary", None) => {
+                if conf.primary || mon_setup.configs.iter().any(|conf| conf.primary) {
+                    return Err(line.error_at(key, "Only one monitor of a setup can be primary"));
+                }
+
+                conf.primary = true;
+            }
+            ("mode", Some(mode)) => {
+                conf.mode = match mode
+                    .split_once('x')
+                    .map(|(w, h)| (w.parse::<u16>(), h.parse::<u16>()))
+                {
+                    Some((Ok(w), Ok(h))) if w > 0 && h > 0 => Some((w, h)),
+                    _ => return Err(invalid(
+                        "Invalid mode (modes are written <width>x<height>, e.g. 'mode=2560x1440')",
+                    )),
+                }
+            }
+            ("rate", Some(rate)) => {
+                conf.rate = match rate.parse::<f64>() {
+                    Ok(x) if x > 0.0 && x.is_finite() => Some(x),
+                    _ => {
+                        return Err(invalid(
+                            "Invalid rate (rate must be a refresh rate in Hz, e.g. 'rate=144')",
+                        ))
+                    }
+                }
+            }
+            ("scale", Some(scale)) => {
+                conf.scale = match scale.parse::<f64>() {
+                    Ok(x) if x > 0.0 && x.is_finite() => x,
+                    _ => {
+                        return Err(invalid(
+                            "Invalid scale (scale must be a positive number, e.g. 'scale=1.5')",
+                        ))
+                    }
+                }
+            }
+            ("reflect", Some(reflect)) => {
+                conf.rot |= match reflect {
+                    "x" => randr::Rotation::REFLECT_X,
+                    "y" => randr::Rotation::REFLECT_Y,
+                    "xy" => randr::Rotation::REFLECT_X | randr::Rotation::REFLECT_Y,
+                    _ => {
+                        return Err(invalid(
+                            "Invalid reflect (reflect can only be 'x', 'y' or 'xy')",
+                        ))
+                    }
+                }
+            }
+            ("primary", Some(_)) => return Err(invalid("'primary' doesn't take a value")),
+            ("mode" | "rate" | "scale" | "reflect", None) => {
+                return Err(line.error_at(
+                    key,
+                    &format_args!("Missing value, {} is written {}=<value>", key.quoted(), key.text)
+                        .to_string(),
+                ))
+            }
+            _ => return Err(line.error_at(
+                key,
+                "Unknown monitor option (options are mode=<w>x<h>, rate=<hz>, scale=<factor>, reflect=<x|y|xy>, primary)",
+            )),
+        }
+    }
+
+    Ok(conf)
+}
+
+/* Setup names end up as command line arguments and in the control protocol, which splits on
+whitespace */
+pub fn amc_valid_setup_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains(|c: char| c.is_whitespace() || c == '/')
+}
+
+/* Parses the lines of a [global] section into `globals`, adding whatever is wrong with them to
+`errors`. Settings given more than once (even across files) take the last value. */
+fn amc_parse_globals(
+    lines: &[lexer::Line],
+    globals: &mut AmcGlobals,
+    errors: &mut Vec<amc::Error>,
+) {
+    for line in lines.iter().filter(|line| !line.is_empty()) {
+        if let Err(err) = amc_parse_global_line(globals, line) {
+            errors.push(err);
+        }
+    }
+}
+
+fn amc_parse_global_line(globals: &mut AmcGlobals, line: &lexer::Line) -> amc::Result<()> {
+    if amc_is_alias(line) {
+        globals.aliases.push(amc_parse_alias(line)?);
+        return Ok(());
+    }
+
+    let (key, value) = amc_split_setting(line)?;
+
+    match key.text {
+        "fallback" => {
+            let fallback = amc_single_value(line, &key, value)?;
+
+            globals.fallback = match fallback.text {
+                "mirror" => AmcFallback::Mirror,
+                "extend" => AmcFallback::Extend,
+                "none" => AmcFallback::Keep,
+                _ => {
+                    return Err(line.error_at(
+                        &fallback,
+                        "Invalid fallback (fallback can only be 'mirror', 'extend' or 'none')",
+                    ))
+                }
+            }
+        }
+        "poll-interval" => {
+            let interval = amc_single_value(line, &key, value)?;
+
+            globals.poll_interval = match interval.text.parse::<f64>() {
+                Ok(x) if x >= 0.1 && x.is_finite() => time::Duration::from_secs_f64(x),
+                _ => {
+                    return Err(line.error_at(
+                        &interval,
+                        "Invalid poll-interval (poll-interval must be a number of seconds, at least 0.1)",
+                    ))
+                }
+            }
+        }
+        // Hooks are shell commands, so they're taken as written
+        "hook" => globals.hook = Some(line.rest_from(&value[0]).to_string()),
+        "enforce" => {
+            let enforce = amc_single_value(line, &key, value)?;
+
+            globals.enforce = match enforce.text {
+                "true" => true,
+                "false" => false,
+                _ => {
+                    return Err(line.error_at(
+                        &enforce,
+                        "Invalid enforce (enforce can only be 'true' or 'false')",
+                    ))
+                }
+            }
+        }
+        _ => {
+            return Err(line.error_at(
+                &key,
+                "Unknown global setting (settings are fallback, poll-interval, hook, enforce and alias)",
+            ))
+        }
+    }
+
+    Ok(())
+}
+
+/* The setup name of a '[setup <name>]' header, None for '[global]' */
+fn amc_parse_section_header<'a>(line: &lexer::Line<'a>) -> amc::Result<Option<&'a str>> {
+    let tokens = &line.tokens;
+
+    let inner = match tokens.last() {
+        Some(last) if last.is(lexer::TokenKind::RBracket) && tokens.len() > 1 => {
+            &tokens[1..tokens.len() - 1]
+        }
+        _ => return Err(line.error_at_end("Missing ']' at the end of the section header")),
+    };
+
+    match inner {
+        [kind] if kind.text == "global" => Ok(None),
+        [kind] if kind.text == "setup" => Err(line.error_at(kind, "Missing setup name")),
+        [kind, name] if kind.text == "setup" => match amc_valid_setup_name(name.text) {
+            true => Ok(Some(name.text)),
+            false => Err(line.error_at(name, "Invalid setup name (names can't have '/' in them)")),
+        },
+        [kind, _, extra @ ..] if kind.text == "setup" => Err(line.error_between(
+            &extra[0],
+            extra.last().unwrap(),
+            "Setup names can't have whitespace in them",
+        )),
+        _ => Err(line.error("Invalid section (sections are '[global]' and '[setup <name>]')")),
+    }
+}
+
+/* A config file split into its sections, with the line each setup starts at */
+#[derive(Default)]
+struct AmcConfigFile<'a> {
+    globals: Vec<&'a [lexer::Line<'a>]>,
+    setups: Vec<(&'a str, usize, &'a [lexer::Line<'a>])>,
+    /* Aliases for every setup of the file */
+    aliases: Vec<(String, u32)>,
+}
+
+/* A config file either is a single setup named after the file (the original format) or, when it
+starts with 'version = 2', is made of sections:
+    [global]        settings for amc as a whole, see amc_parse_globals
+    [setup <name>]  a setup, written the same way as a file in the original format
+along with aliases for the whole file before the first section. Sections are only parsed once
+every file has been split, so that global aliases can be used in any file. */
+fn amc_split_config_file<'a>(
+    file_name: &'a str,
+    lines: &'a [lexer::Line<'a>],
+    errors: &mut Vec<amc::Error>,
+) -> AmcConfigFile<'a> {
+    let mut file = AmcConfigFile::default();
+
+    let version = lines
+        .iter()
+        .position(|line| !line.is_empty())
+        .filter(|&i| lines[i].tokens[0].text == "version");
+
+    let version = match version {
+        Some(i) => {
+            let line = &lines[i];
+            let version = match amc_split_setting(line)
+                .and_then(|(key, value)| amc_single_value(line, &key, value))
+            {
+                Ok(x) => x,
+                Err(err) => {
+                    errors.push(err);
+                    return file;
+                }
+            };
+
+            match version.text {
+                "1" | "2" => Some((i, version.text)),
+                _ => {
+                    errors.push(line.error_at(
+                        &version,
+                        "Unsupported config version (amc understands versions 1 and 2)",
+                    ));
+                    return file;
+                }
+            }
+        }
+        None => None,
+    };
+
+    match version {
+        None => file.setups.push((file_name, 1, lines)),
+        Some((i, "1")) => file.setups.push((file_name, 1, &lines[i + 1..])),
+        Some((i, _)) => {
+            let headers = (i + 1..lines.len())
+                .filter(|&j| {
+                    lines[j]
+                        .tokens
+                        .first()
+                        .is_some_and(|token| token.is(lexer::TokenKind::LBracket))
+                })
+                .collect::<Vec<usize>>();
+
+            let first_section = headers.first().copied().unwrap_or(lines.len());
+            for line in lines[i + 1..first_section]
+                .iter()
+                .filter(|line| !line.is_empty())
+            {
+                match amc_is_alias(line) {
+                    true => match amc_parse_alias(line) {
+                        Ok(alias) => file.aliases.push(alias),
+                        Err(err) => errors.push(err),
+                    },
+                    false => errors.push(line.error(
+                        "Settings must go in a '[global]' or '[setup <name>]' section, only aliases can come before them",
+                    )),
+                }
+            }
+
+            for (k, &header) in headers.iter().enumerate() {
+                let body = &lines[header + 1..headers.get(k + 1).copied().unwrap_or(lines.len())];
+
+                match amc_parse_section_header(&lines[header]) {
+                    Ok(None) => file.globals.push(body),
+                    Ok(Some(name)) => file.setups.push((name, lines[header].n, body)),
+                    Err(err) => errors.push(err),
+                }
+            }
+        }
+    }
+
+    file
+}
+
+/* The most recent modification time of the config dir or any file in it */
+pub fn amc_config_dir_stamp(dir: &str) -> Option<std::time::SystemTime> {
+    let mut stamp = std::fs::metadata(dir)
+        .and_then(|meta| meta.modified())
+        .ok()?;
+
+    for dir in std::iter::once(dir.to_string()).chain(amc_host_config_dir(dir)) {
+        if let Ok(modified) = std::fs::metadata(&dir).and_then(|meta| meta.modified()) {
+            stamp = std::cmp::max(stamp, modified);
+        }
+
+        if let Ok(files) = amc_list_config_files(&dir) {
+            for file in files {
+                if let Ok(modified) = file.metadata().and_then(|meta| meta.modified()) {
+                    stamp = std::cmp::max(stamp, modified);
+                }
+            }
+        }
+    }
+
+    Some(stamp)
+}
+
+/* The dir holding the config files only read on this machine, 'hosts/<hostname>' in the config
+dir. It doesn't have to exist. */
+fn amc_host_config_dir(dir: &str) -> Option<String> {
+    amc::hostname().map(|host| format_args!("{}/hosts/{}", dir, host).to_string())
+}
+
+/* The files in `dir`, without subdirs such as 'hosts' */
+fn amc_list_config_files(dir: &str) -> amc::Result<Vec<std::fs::DirEntry>> {
+    let mut files = match std::fs::read_dir(dir) {
+        Ok(files) => files.collect::<Result<Vec<_>, std::io::Error>>()?, // why tf can this even be Err?
+        Err(err) => {
+            return Err(
+                format_args!("Could not list files in dir '{}'\n  {}", dir, err)
+                    .to_string()
+                    .into(),
+            )
+        }
+    };
+    /* Only .conf files, so editor backups and swap files next to them don't end up as setups */
+    files.retain(|file| {
+        let path = file.path();
+        !path.is_dir() && path.extension().is_some_and(|ext| ext == "conf")
+    });
+
+    // Sorted by name so that cycling through setups happens in a predictable order
+    files.sort_by_key(|file| file.file_name());
+
+    Ok(files)
+}
+
+pub fn amc_read_config_from_dir(dir: &str) -> amc::Result<AmcConfig> {
+    match std::fs::exists(dir) {
+        Ok(true) => (),
+        Ok(false) => {
+            if let Err(err) = std::fs::create_dir(dir) {
+                return Err(
+                    format_args!("Could not create config dir '{}'\n  {}", dir, err)
+                        .to_string()
+                        .into(),
+                );
+            };
+        }
+        Err(err) => {
+            return Err(format_args!(
+                "Could not check existance of config dir '{}'\n  {}",
+                dir, err
+            )
+            .to_string()
+            .into())
+        }
+    };
+
+    /* The files of the host dir come last, so that their settings win over the others and their
+    setups replace those with the same name */
+    let mut files = amc_list_config_files(dir)?
+        .into_iter()
+        .map(|file| (file, false))
+        .collect::<Vec<(std::fs::DirEntry, bool)>>();
+
+    if let Some(host_dir) = amc_host_config_dir(dir).filter(|host_dir| Path::new(host_dir).is_dir())
+    {
+        files.extend(
+            amc_list_config_files(&host_dir)?
+                .into_iter()
+                .map(|file| (file, true)),
+        );
+    }
+
+    // Every file is read even if some are broken, so that all errors get reported at once
+    let mut errors = Vec::<amc::Error>::new();
+    // (path, setup name for the original format, content, from the host dir) of each readable file
+    let mut contents = Vec::<(String, String, String, bool)>::new();
+
+    for (file, from_host_dir) in files {
+        let path = file.path().to_string_lossy().to_string();
+
+        let file_content = match std::fs::read_to_string(file.path()) {
+            Ok(x) => x,
+            Err(err) => {
+                errors.push(
+                    format_args!("Could not read config file '{}'\n  {}", path, err)
+                        .to_string()
+                        .into(),
+                );
+                continue;
+            }
+        };
+
+        let name = match file.path().file_stem() {
+            Some(stem) => stem.to_string_lossy().to_string(),
+            None => file.file_name().to_string_lossy().to_string(),
+        };
+
+        contents.push((path, name, file_content, from_host_dir));
+    }
+
+    let config = amc_parse_config_files(&contents, &mut errors);
+
+    if !errors.is_empty() {
+        return Err(amc::Error::many(errors));
+    }
+
+    Ok(config)
+}
+
+/* Parses the config files read from the config dir, given as (path, setup name for the original
+format, content, from the host dir) in the order they're read, adding whatever is wrong with them
+to `errors` */
+fn amc_parse_config_files(
+    contents: &[(String, String, String, bool)],
+    errors: &mut Vec<amc::Error>,
+) -> AmcConfig {
+    let lines = contents
+        .iter()
+        .map(|(_, _, content, _)| lexer::tokenize(content, 1))
+        .collect::<Vec<Vec<lexer::Line>>>();
+    let mut file_errors = contents
+        .iter()
+        .map(|_| Vec::<amc::Error>::new())
+        .collect::<Vec<Vec<amc::Error>>>();
+
+    let config_files = contents
+        .iter()
+        .zip(&lines)
+        .zip(&mut file_errors)
+        .map(|(((_, name, _, _), lines), errors)| amc_split_config_file(name, lines, errors))
+        .collect::<Vec<AmcConfigFile>>();
+
+    let mut config = AmcConfig::default();
+
+    // Globals first, as every setup can use the global aliases
+    for (file, errors) in config_files.iter().zip(&mut file_errors) {
+        for body in &file.globals {
+            amc_parse_globals(body, &mut config.globals, errors);
+        }
+    }
+
+    /* (file, name, body) of every setup, its first definition winning over later ones, except
+    for those of the host dir which replace the others */
+    let mut sections = Vec::<(usize, &str, &[lexer::Line])>::new();
+
+    for (i, file) in config_files.iter().enumerate() {
+        for &(name, line, body) in &file.setups {
+            if let Some(k) = sections.iter().position(|section| section.1 == name) {
+                let other = sections[k].0;
+
+                if contents[i].3 && !contents[other].3 {
+                    sections[k] = (i, name, body);
+                    continue;
+                }
+
+                let msg = format_args!(
+                    "Setup '{}' is already defined in '{}'",
+                    name, contents[other].0
+                )
+                .to_string();
+
+                // Pointing at its section header, or the top of the file for the original format
+                file_errors[i].push(match lines[i].get(line - 1) {
+                    Some(line) => line.error(&msg),
+                    None => amc::Error::config(line, 1, 0, "", &msg),
+                });
+                continue;
+            }
+
+            sections.push((i, name, body));
+        }
+    }
+
+    // The section of the setup each one extends, if any
+    let bases = sections
+        .iter()
+        .map(|&(i, _, body)| {
+            let (line, name) = amc_find_extends(body)?;
+
+            match sections.iter().position(|section| section.1 == name.text) {
+                Some(base) => Some(base),
+                None => {
+                    file_errors[i].push(line.error_at(
+                        &name,
+                        &format_args!("Unknown setup {} to extend", name.quoted()).to_string(),
+                    ));
+                    None
+                }
+            }
+        })
+        .collect::<Vec<Option<usize>>>();
+
+    let mut setups = sections
+        .iter()
+        .map(|_| None)
+        .collect::<Vec<Option<MonitorSetup>>>();
+
+    /* Setups get parsed after the one they extend, following each chain of setups up to one
+    that's already parsed or extends nothing. A setup closing a loop is reported and parsed as
+    if it extended nothing. */
+    for k in 0..sections.len() {
+        if setups[k].is_some() {
+            continue;
+        }
+
+        let mut chain = vec![k];
+        let mut loop_end = None;
+
+        while let Some(base) = bases[*chain.last().unwrap()] {
+            if setups[base].is_some() {
+                break;
+            }
+
+            if let Some(start) = chain.iter().position(|&j| j == base) {
+                let end = *chain.last().unwrap();
+                let (i, _, body) = sections[end];
+                let (line, name) = amc_find_extends(body).unwrap();
+
+                let msg = match start == chain.len() - 1 {
+                    true => "A setup can't extend itself".to_string(),
+                    false => format_args!(
+                        "Setups can't extend each other in a loop ({} -> {})",
+                        chain[start..]
+                            .iter()
+                            .map(|&j| sections[j].1)
+                            .collect::<Vec<&str>>()
+                            .join(" -> "),
+                        sections[base].1
+                    )
+                    .to_string(),
+                };
+
+                file_errors[i].push(line.error_at(&name, &msg));
+                loop_end = Some(end);
+                break;
+            }
+
+            chain.push(base);
+        }
+
+        for &j in chain.iter().rev() {
+            let (i, name, body) = sections[j];
+
+            let mut aliases = config.globals.aliases.clone();
+            aliases.extend(config_files[i].aliases.iter().cloned());
+
+            let base = match bases[j] {
+                Some(base) if loop_end != Some(j) => setups[base].as_ref(),
+                _ => None,
+            };
+
+            let setup = amc_parse_setup(name, body, &aliases, base, &mut file_errors[i]);
+            setups[j] = Some(setup);
+        }
+    }
+
+    config.setups = setups.into_iter().flatten().collect();
+
+    for ((path, _, _, _), file_errors) in contents.iter().zip(file_errors) {
+        if !file_errors.is_empty() {
+            errors.push(amc::Error::many(file_errors).in_file(path));
+        }
+    }
+
+    config
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(content: &str) -> (MonitorSetup, Vec<amc::Error>) {
+        let lines = lexer::tokenize(content, 1);
+        let mut errors = Vec::<amc::Error>::new();
+        let mon_setup = amc_parse_setup("test", &lines, &[], None, &mut errors);
+
+        (mon_setup, errors)
+    }
+
+    /* The message, line, column and length of the only error parsing `content` gives */
+    fn parse_error(content: &str) -> (String, usize, usize, usize) {
+        let (_, errors) = parse(content);
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+
+        match &errors[0] {
+            amc::Error::Config {
+                msg,
+                line,
+                column,
+                length,
+                ..
+            } => (msg.clone(), *line, *column, *length),
+            err => panic!("Not a config error: {}", err),
+        }
+    }
+
+    /* Parses config files given as (file name, content) the way they'd be read from the config
+    dir */
+    fn read(files: &[(&str, &str)]) -> (AmcConfig, Vec<amc::Error>) {
+        let contents = files
+            .iter()
+            .map(|(file, content)| {
+                let name = file.trim_end_matches(".conf");
+                (
+                    file.to_string(),
+                    name.to_string(),
+                    content.to_string(),
+                    false,
+                )
+            })
+            .collect::<Vec<(String, String, String, bool)>>();
+
+        let mut errors = Vec::<amc::Error>::new();
+        let config = amc_parse_config_files(&contents, &mut errors);
+
+        (config, errors)
+    }
+
+    fn setup<'a>(config: &'a AmcConfig, name: &str) -> &'a MonitorSetup {
+        config
+            .setups
+            .iter()
+            .find(|setup| setup.name == name)
+            .unwrap()
+    }
+
+    fn header_error(text: &str) -> (String, usize, usize) {
+        let lines = lexer::tokenize(text, 1);
+
+        match amc_parse_section_header(&lines[0]) {
+            Err(amc::Error::Config {
+                msg,
+                column,
+                length,
+                ..
+            }) => (msg, column, length),
+            result => panic!("Not a config error: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn monitors_are_parsed() {
+        let (mon_setup, errors) = parse(
+            "priority = 5 # over the others\n\
+             409e9d21 = -1920x0, 90, mode=1920x1080, rate=59.95, scale=1.5, reflect=x, primary\n\
+             864cca8f = off\n\
+             Xft.dpi: 96\n",
+        );
+        assert!(errors.is_empty(), "{:?}", errors);
+
+        assert_eq!(mon_setup.priority, 5);
+        assert_eq!(mon_setup.configs.len(), 2);
+
+        let conf = &mon_setup.configs[0];
+        assert_eq!((conf.id, conf.x, conf.y), (0x409e9d21, -1920, 0));
+        assert_eq!(
+            conf.rot,
+            randr::Rotation::ROTATE_90 | randr::Rotation::REFLECT_X
+        );
+        assert_eq!(conf.mode, Some((1920, 1080)));
+        assert_eq!(conf.rate, Some(59.95));
+        assert_eq!(conf.scale, 1.5);
+        assert!(conf.primary && !conf.off);
+
+        assert_eq!(mon_setup.configs[1].id, 0x864cca8f);
+        assert!(mon_setup.configs[1].off);
+
+        assert_eq!(
+            mon_setup.xresources,
+            vec![("Xft.dpi".to_string(), "96".to_string())]
+        );
+    }
+
+    #[test]
+    fn aliases_can_be_used_before_they_are_defined() {
+        let (mon_setup, errors) = parse("laptop = 0x0, 0\nalias laptop = 76395efc\n");
+        assert!(errors.is_empty(), "{:?}", errors);
+
+        assert_eq!(mon_setup.configs[0].id, 0x76395efc);
+    }
+
+    #[test]
+    fn every_bad_line_is_reported() {
+        let (_, errors) = parse("409e9d21 = 0x0, 45\n864cca8f = 0x0\nnope = off\n");
+
+        assert_eq!(
+            errors
+                .iter()
+                .map(|err| match err {
+                    amc::Error::Config { line, .. } => *line,
+                    _ => 0,
+                })
+                .collect::<Vec<usize>>(),
+            vec![1, 2, 3]
+        );
+    }
+
+    #[test]
+    fn bad_rotation() {
+        let (msg, line, column, length) = parse_error("\n409e9d21 = 0x0, 45\n");
+
+        assert!(msg.starts_with("Invalid monitor rotation"), "{}", msg);
+        assert_eq!((line, column, length), (2, 17, 2));
+    }
+
+    #[test]
+    fn missing_rotation() {
+        let (msg, line, column, length) = parse_error("409e9d21 = 0x0  # no rotation");
+
+        assert_eq!(msg, "Missing monitor rotation");
+        assert_eq!((line, column, length), (1, 15, 1));
+    }
+
+    #[test]
+    fn bad_position() {
+        let (msg, line, column, length) = parse_error("409e9d21 = 0x99999, 0");
+
+        assert!(msg.starts_with("Invalid monitor position"), "{}", msg);
+        assert_eq!((line, column, length), (1, 12, 7));
+    }
+
+    #[test]
+    fn bad_mode() {
+        let (msg, line, column, length) = parse_error("409e9d21 = 0x0, 0, mode=1920×1080");
+
+        assert!(msg.starts_with("Invalid mode"), "{}", msg);
+        assert_eq!((line, column, length), (1, 25, 9));
+    }
+
+    #[test]
+    fn bad_rate() {
+        let (msg, line, column, length) = parse_error("409e9d21 = 0x0, 0, rate=-60");
+
+        assert!(msg.starts_with("Invalid rate"), "{}", msg);
+        assert_eq!((line, column, length), (1, 25, 3));
+    }
+
+    #[test]
+    fn bad_scale() {
+        let (msg, line, column, length) = parse_error("409e9d21 = 0x0, 0, scale=0");
+
+        assert!(msg.starts_with("Invalid scale"), "{}", msg);
+        assert_eq!((line, column, length), (1, 26, 1));
+
+        let (msg, _, column, length) = parse_error("409e9d21 = 0x0, 0, scale");
+
+        assert_eq!(msg, "Missing value, 'scale' is written scale=<value>");
+        assert_eq!((column, length), (20, 5));
+    }
+
+    #[test]
+    fn values_need_commas() {
+        let (msg, line, column, length) = parse_error("409e9d21 = 0x0 90");
+
+        assert_eq!(msg, "Unexpected '90' (values are separated by ',')");
+        assert_eq!((line, column, length), (1, 16, 2));
+    }
+
+    #[test]
+    fn two_primaries() {
+        let (msg, line, column, length) =
+            parse_error("409e9d21 = 0x0, 0, primary\n864cca8f = 0x0, 0, primary");
+
+        assert_eq!(msg, "Only one monitor of a setup can be primary");
+        assert_eq!((line, column, length), (2, 20, 7));
+    }
+
+    #[test]
+    fn section_headers() {
+        let lines = lexer::tokenize("[global]\n[setup desk] # comment", 1);
+
+        assert_eq!(amc_parse_section_header(&lines[0]).unwrap(), None);
+        assert_eq!(amc_parse_section_header(&lines[1]).unwrap(), Some("desk"));
+    }
+
+    #[test]
+    fn bad_section_headers() {
+        assert_eq!(
+            header_error("[setup desk"),
+            (
+                "Missing ']' at the end of the section header".to_string(),
+                12,
+                1
+            )
+        );
+        assert_eq!(
+            header_error("[setup]"),
+            ("Missing setup name".to_string(), 2, 5)
+        );
+        assert_eq!(
+            header_error("[setup my desk]"),
+            (
+                "Setup names can't have whitespace in them".to_string(),
+                11,
+                4
+            )
+        );
+        assert_eq!(
+            header_error("[setup a/b]"),
+            (
+                "Invalid setup name (names can't have '/' in them)".to_string(),
+                8,
+                3
+            )
+        );
+        assert_eq!(
+            header_error("  [écrans]  # sections"),
+            (
+                "Invalid section (sections are '[global]' and '[setup <name>]')".to_string(),
+                3,
+                8
+            )
+        );
+    }
+
+    #[test]
+    fn extends_inherits_and_overrides() {
+        let (config, errors) = read(&[(
+            "desk.conf",
+            "version = 2\n\
+             [setup desk]\n\
+             priority = 10\n\
+             dpi = 144\n\
+             host = workstation\n\
+             panels = 76395efc\n\
+             409e9d21 = 0x0, 0\n\
+             76395efc = 1920x0, 0, primary\n\
+             Xft.dpi: 144\n\
+             [setup desk-rotated]\n\
+             extends = desk\n\
+             priority = 20\n\
+             409e9d21 = 0x0, 90, primary\n\
+             864cca8f = 3840x0, 0\n",
+        )]);
+        assert!(errors.is_empty(), "{:?}", errors);
+
+        let rotated = setup(&config, "desk-rotated");
+        assert_eq!(rotated.extends.as_deref(), Some("desk"));
+        assert_eq!(rotated.priority, 20);
+        assert_eq!(rotated.dpi, amc::DpiPolicy::Fixed(144.0));
+        assert_eq!(rotated.host.as_deref(), Some("workstation"));
+        assert_eq!(rotated.panels, Some(vec![0x76395efc]));
+        assert_eq!(rotated.xresources, setup(&config, "desk").xresources);
+
+        let configs = rotated
+            .configs
+            .iter()
+            .map(|conf| (conf.id, conf.x, conf.rot, conf.primary))
+            .collect::<Vec<(u32, i16, randr::Rotation, bool)>>();
+        assert_eq!(
+            configs,
+            vec![
+                (0x409e9d21, 0, randr::Rotation::ROTATE_90, true),
+                (0x76395efc, 1920, randr::Rotation::ROTATE_0, false),
+                (0x864cca8f, 3840, randr::Rotation::ROTATE_0, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn extends_follows_several_levels_across_files() {
+        /* Each setup comes before the one it extends, so they can't be parsed in file order */
+        let (config, errors) = read(&[
+            ("a.conf", "extends = b\n864cca8f = 3840x0, 0\n"),
+            ("b.conf", "extends = c\n76395efc = 1920x0, 0\n"),
+            ("c.conf", "priority = 3\n409e9d21 = 0x0, 0\n"),
+        ]);
+        assert!(errors.is_empty(), "{:?}", errors);
+
+        let a = setup(&config, "a");
+        assert_eq!(a.priority, 3);
+        assert_eq!(
+            a.configs.iter().map(|conf| conf.id).collect::<Vec<u32>>(),
+            vec![0x409e9d21, 0x76395efc, 0x864cca8f]
+        );
+        assert_eq!(setup(&config, "b").configs.len(), 2);
+    }
+
+    #[test]
+    fn extends_can_turn_off_a_monitor() {
+        let (config, errors) = read(&[(
+            "desk.conf",
+            "version = 2\n\
+             [setup desk]\n\
+             409e9d21 = 0x0, 0\n\
+             76395efc = 1920x0, 0, primary\n\
+             [setup desk-panel-off]\n\
+             extends = desk\n\
+             76395efc = off\n",
+        )]);
+        assert!(errors.is_empty(), "{:?}", errors);
+
+        let panel_off = setup(&config, "desk-panel-off");
+        assert_eq!(panel_off.configs.len(), 2);
+        assert!(!panel_off.configs[0].off);
+        assert!(panel_off.configs[1].off && !panel_off.configs[1].primary);
+
+        assert!(!setup(&config, "desk").configs[1].off);
+    }
+
+    #[test]
+    fn extends_loops_are_reported() {
+        let (config, errors) = read(&[(
+            "loop.conf",
+            "version = 2\n\
+             [setup a]\n\
+             extends = b\n\
+             [setup b]\n\
+             extends = c\n\
+             [setup c]\n\
+             extends = a\n\
+             [setup self]\n\
+             extends = self\n\
+             [setup lost]\n\
+             extends = nowhere\n",
+        )]);
+
+        let errors = amc::Error::many(errors);
+        let msgs = errors
+            .errors()
+            .iter()
+            .map(|err| match err {
+                amc::Error::Config { line, msg, .. } => (*line, msg.as_str()),
+                err => panic!("Not a config error: {}", err),
+            })
+            .collect::<Vec<(usize, &str)>>();
+
+        assert_eq!(
+            msgs,
+            vec![
+                (11, "Unknown setup 'nowhere' to extend"),
+                (
+                    7,
+                    "Setups can't extend each other in a loop (a -> b -> c -> a)"
+                ),
+                (9, "A setup can't extend itself"),
+            ]
+        );
+
+        /* Every setup still gets parsed, so that their own mistakes are reported too */
+        assert_eq!(config.setups.len(), 5);
+    }
+}
@@ -0,0 +1,240 @@
+// Copyright Olaru Alexandru
+// Licensed under the MIT license
+
+/* Control socket used to talk to a running amc. The protocol is line based: the
+client sends a single command line and the daemon answers with either "ok" followed
+by the command's output, or "error" followed by an error message, then closes the
+connection. */
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::time::Duration;
+
+use crate::amc;
+
+#[derive(Debug)]
+pub enum Command {
+    /* With `json` set the output is meant for scripts, see the README for its schema */
+    Status { json: bool },
+    Reapply,
+    Reload,
+    /* When `pin` is set the daemon sticks to the setup until the connected monitors change */
+    Apply { setup: String, pin: bool },
+    Next,
+    Prev,
+    Save,
+    ListMonitors,
+}
+
+impl Command {
+    pub fn parse(line: &str) -> amc::Result<Command> {
+        let mut words = line.split_whitespace();
+
+        let cmd = match words.next() {
+            Some(x) => x,
+            None => return Err("Empty command".into()),
+        };
+
+        let command = match cmd {
+            "status" => Command::Status {
+                json: match words.next() {
+                    Some("json") => true,
+                    Some(arg) => {
+                        return Err(
+                            format_args!("Invalid argument '{}' for command 'status'", arg)
+                                .to_string()
+                                .into(),
+                        )
+                    }
+                    None => false,
+                },
+            },
+            "reapply" => Command::Reapply,
+            "reload" => Command::Reload,
+            "next" => Command::Next,
+            "prev" => Command::Prev,
+            "save" => Command::Save,
+            "list-monitors" => Command::ListMonitors,
+            "apply" => {
+                let setup = match words.next() {
+                    Some(x) => x.to_string(),
+                    None => return Err("Command 'apply' requires a setup name".into()),
+                };
+
+                let pin = match words.next() {
+                    Some("pin") => true,
+                    Some(arg) => {
+                        return Err(
+                            format_args!("Invalid argument '{}' for command 'apply'", arg)
+                                .to_string()
+                                .into(),
+                        )
+                    }
+                    None => false,
+                };
+
+                Command::Apply { setup, pin }
+            }
+            invalid => {
+                return Err(format_args!("Unknown command '{}'", invalid)
+                    .to_string()
+                    .into())
+            }
+        };
+
+        if words.next().is_some() {
+            return Err(format_args!("Too many arguments for command '{}'", cmd)
+                .to_string()
+                .into());
+        }
+
+        Ok(command)
+    }
+
+    pub fn to_line(&self) -> String {
+        match self {
+            Command::Status { json: false } => "status".to_string(),
+            Command::Status { json: true } => "status json".to_string(),
+            Command::Reapply => "reapply".to_string(),
+            Command::Reload => "reload".to_string(),
+            Command::Apply { setup, pin: false } => format_args!("apply {}", setup).to_string(),
+            Command::Apply { setup, pin: true } => format_args!("apply {} pin", setup).to_string(),
+            Command::Next => "next".to_string(),
+            Command::Prev => "prev".to_string(),
+            Command::Save => "save".to_string(),
+            Command::ListMonitors => "list-monitors".to_string(),
+        }
+    }
+}
+
+pub fn default_socket_path() -> String {
+    match std::env::var("XDG_RUNTIME_DIR") {
+        Ok(runtime_dir) => format_args!("{}/amc.sock", runtime_dir).to_string(),
+        Err(_) => format_args!("/tmp/amc-{}.sock", unsafe { libc::getuid() }).to_string(),
+    }
+}
+
+pub struct Server {
+    listener: UnixListener,
+    path: String,
+}
+
+impl Server {
+    pub fn bind(path: &str) -> amc::Result<Server> {
+        if std::fs::exists(path).unwrap_or(false) {
+            if UnixStream::connect(path).is_ok() {
+                return Err(format_args!(
+                    "Another amc instance is already listening on '{}'",
+                    path
+                )
+                .to_string()
+                .into());
+            }
+
+            // Left behind by an amc that didn't exit cleanly
+            if let Err(err) = std::fs::remove_file(path) {
+                return Err(format_args!(
+                    "Could not remove stale control socket '{}'\n  {}",
+                    path, err
+                )
+                .to_string()
+                .into());
+            }
+        }
+
+        let listener = match UnixListener::bind(path) {
+            Ok(x) => x,
+            Err(err) => {
+                return Err(
+                    format_args!("Could not bind control socket '{}'\n  {}", path, err)
+                        .to_string()
+                        .into(),
+                )
+            }
+        };
+
+        listener.set_nonblocking(true)?;
+
+        Ok(Server {
+            listener,
+            path: path.to_string(),
+        })
+    }
+
+    /* Blocks until a client connects or the timeout runs out. Returns true if there
+    is a client waiting to be accepted. */
+    pub fn wait(&self, timeout: Duration) -> bool {
+        let mut pfd = libc::pollfd {
+            fd: self.listener.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+
+        let timeout_ms = std::cmp::min(timeout.as_millis(), i32::MAX as u128) as i32;
+
+        (unsafe { libc::poll(&mut pfd, 1, timeout_ms) }) > 0 && pfd.revents & libc::POLLIN != 0
+    }
+
+    /* Accepts a client and reads its command. The returned stream is where the reply
+    should be written to with `reply`. */
+    pub fn accept(&self) -> amc::Result<(UnixStream, amc::Result<Command>)> {
+        let (stream, _) = self.listener.accept()?;
+
+        // The listener is non blocking but we want simple blocking io with the client
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+        stream.set_write_timeout(Some(Duration::from_secs(1)))?;
+
+        let mut line = String::new();
+        BufReader::new(&stream).read_line(&mut line)?;
+
+        Ok((stream, Command::parse(&line)))
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+pub fn reply(mut stream: UnixStream, result: amc::Result<String>) {
+    let msg = match result {
+        Ok(body) => format_args!("ok\n{}", body).to_string(),
+        Err(err) => format_args!("error\n{}", err).to_string(),
+    };
+
+    // Nothing to be done if the client went away
+    let _ = stream.write_all(msg.as_bytes());
+}
+
+pub fn is_running(path: &str) -> bool {
+    UnixStream::connect(path).is_ok()
+}
+
+/* Sends a command to the amc listening on `path` and returns its output */
+pub fn request(path: &str, cmd: &Command) -> amc::Result<String> {
+    let mut stream = match UnixStream::connect(path) {
+        Ok(x) => x,
+        Err(err) => {
+            return Err(format_args!(
+                "Could not connect to amc on '{}', is it running?\n  {}",
+                path, err
+            )
+            .to_string()
+            .into())
+        }
+    };
+
+    stream.write_all(format_args!("{}\n", cmd.to_line()).to_string().as_bytes())?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+
+    match response.split_once('\n') {
+        Some(("ok", body)) => Ok(body.to_string()),
+        Some(("error", msg)) => Err(msg.to_string().into()),
+        _ => Err("Malformed response from amc".into()),
+    }
+}
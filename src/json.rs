@@ -0,0 +1,213 @@
+// Copyright Olaru Alexandru
+// Licensed under the MIT license
+
+/* Just enough JSON to print what amc knows for scripts, not worth pulling in serde for. Objects
+keep their keys in the order they were added so the output is stable. */
+
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    pub fn object(fields: Vec<(&str, Value)>) -> Value {
+        Value::Object(
+            fields
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value))
+                .collect(),
+        )
+    }
+
+    fn write(&self, f: &mut fmt::Formatter, indent: usize) -> fmt::Result {
+        match self {
+            Value::Null => write!(f, "null"),
+            Value::Bool(x) => write!(f, "{}", x),
+            // Integers (ids, sizes, positions) shouldn't come out as 1920.0
+            Value::Number(x) if x.fract() == 0.0 && x.abs() < 1e15 => write!(f, "{}", *x as i64),
+            Value::Number(x) if x.is_finite() => write!(f, "{}", x),
+            Value::Number(_) => write!(f, "null"),
+            Value::String(x) => write_string(f, x),
+            Value::Array(values) if values.is_empty() => write!(f, "[]"),
+            Value::Array(values) => {
+                writeln!(f, "[")?;
+                for (i, value) in values.iter().enumerate() {
+                    write!(f, "{:1$}", "", (indent + 1) * 2)?;
+                    value.write(f, indent + 1)?;
+                    writeln!(f, "{}", if i + 1 < values.len() { "," } else { "" })?;
+                }
+                write!(f, "{:1$}]", "", indent * 2)
+            }
+            Value::Object(fields) if fields.is_empty() => write!(f, "{{}}"),
+            Value::Object(fields) => {
+                writeln!(f, "{{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    write!(f, "{:1$}", "", (indent + 1) * 2)?;
+                    write_string(f, key)?;
+                    write!(f, ": ")?;
+                    value.write(f, indent + 1)?;
+                    writeln!(f, "{}", if i + 1 < fields.len() { "," } else { "" })?;
+                }
+                write!(f, "{:1$}}}", "", indent * 2)
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+/* Pretty printed with two space indentation */
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write(f, 0)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(x: bool) -> Value {
+        Value::Bool(x)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(x: &str) -> Value {
+        Value::String(x.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(x: String) -> Value {
+        Value::String(x)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(x: f64) -> Value {
+        Value::Number(x)
+    }
+}
+
+impl From<i16> for Value {
+    fn from(x: i16) -> Value {
+        Value::Number(x as f64)
+    }
+}
+
+impl From<u16> for Value {
+    fn from(x: u16) -> Value {
+        Value::Number(x as f64)
+    }
+}
+
+impl From<i32> for Value {
+    fn from(x: i32) -> Value {
+        Value::Number(x as f64)
+    }
+}
+
+impl From<u32> for Value {
+    fn from(x: u32) -> Value {
+        Value::Number(x as f64)
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(x: Option<T>) -> Value {
+        match x {
+            Some(x) => x.into(),
+            None => Value::Null,
+        }
+    }
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(x: Vec<T>) -> Value {
+        Value::Array(x.into_iter().map(|x| x.into()).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strings_are_escaped() {
+        assert_eq!(Value::from("plain").to_string(), r#""plain""#);
+        assert_eq!(
+            Value::from("say \"hi\" C:\\amc").to_string(),
+            r#""say \"hi\" C:\\amc""#
+        );
+        assert_eq!(Value::from("a\nb\rc\td").to_string(), r#""a\nb\rc\td""#);
+        assert_eq!(
+            Value::from("\u{0}\u{1}\u{1b}\u{1f}").to_string(),
+            r#""\u0000\u0001\u001b\u001f""#
+        );
+        assert_eq!(
+            Value::from("écran ✓ \u{7f}").to_string(),
+            "\"écran ✓ \u{7f}\""
+        );
+    }
+
+    #[test]
+    fn integers_have_no_fraction() {
+        assert_eq!(Value::from(1920u16).to_string(), "1920");
+        assert_eq!(Value::from(-1920i16).to_string(), "-1920");
+        assert_eq!(Value::from(0x409e9d21u32).to_string(), "1084136737");
+        assert_eq!(Value::from(60.0).to_string(), "60");
+        assert_eq!(Value::from(-0.0).to_string(), "0");
+    }
+
+    #[test]
+    fn floats_keep_their_digits() {
+        assert_eq!(Value::from(59.95).to_string(), "59.95");
+        assert_eq!(Value::from(1.25).to_string(), "1.25");
+        assert_eq!(Value::from(-0.5).to_string(), "-0.5");
+        assert_eq!(Value::from(1e15).to_string(), "1000000000000000");
+        assert_eq!(Value::from(0.0000001).to_string(), "0.0000001");
+    }
+
+    #[test]
+    fn non_finite_numbers_are_null() {
+        assert_eq!(Value::from(f64::NAN).to_string(), "null");
+        assert_eq!(Value::from(f64::INFINITY).to_string(), "null");
+        assert_eq!(Value::from(f64::NEG_INFINITY).to_string(), "null");
+    }
+
+    #[test]
+    fn nested_values_are_indented() {
+        let value = Value::object(vec![
+            ("setup", Some("desk").into()),
+            ("pinned", false.into()),
+            ("hook", None::<String>.into()),
+            ("modes", vec![1920u16, 1080u16].into()),
+            ("aliases", Vec::<String>::new().into()),
+            ("edid", Value::object(vec![])),
+        ]);
+
+        assert_eq!(
+            value.to_string(),
+            "{\n  \"setup\": \"desk\",\n  \"pinned\": false,\n  \"hook\": null,\n  \"modes\": [\n    1920,\n    1080\n  ],\n  \"aliases\": [],\n  \"edid\": {}\n}"
+        );
+    }
+}
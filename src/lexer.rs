@@ -0,0 +1,268 @@
+// Copyright Olaru Alexandru
+// Licensed under the MIT license
+
+/* Splits config files (and the state file) into tokens that remember where they came from, so
+that errors can point at the exact spot they're about. Every setting fits on a single line, so
+files are handled line by line. A '#' at the start of a line or after whitespace starts a
+comment that runs to the end of the line, one in the middle of a word is part of it. */
+
+use crate::amc;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TokenKind {
+    /* Anything up to whitespace or one of the characters below, e.g. '409e9d21', '-1920x0',
+    ':1.0' */
+    Word,
+    Equals,
+    Comma,
+    LBracket,
+    RBracket,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Token<'a> {
+    pub kind: TokenKind,
+    pub text: &'a str,
+    /* Byte offset in the line */
+    offset: usize,
+}
+
+impl Token<'_> {
+    pub fn is(&self, kind: TokenKind) -> bool {
+        self.kind == kind
+    }
+
+    /* Quoted the way errors mention it */
+    pub fn quoted(&self) -> String {
+        format_args!("'{}'", self.text).to_string()
+    }
+}
+
+#[derive(Debug)]
+pub struct Line<'a> {
+    /* Starts at 1 */
+    pub n: usize,
+    pub text: &'a str,
+    /* Empty for blank lines and comments */
+    pub tokens: Vec<Token<'a>>,
+    /* Byte offset the comment starts at, or the length of the line without one */
+    comment: usize,
+}
+
+impl<'a> Line<'a> {
+    fn tokenize(n: usize, text: &'a str) -> Line<'a> {
+        let mut tokens = Vec::<Token>::new();
+
+        let comment = text
+            .char_indices()
+            .find(|&(offset, c)| {
+                c == '#'
+                    && text[..offset]
+                        .chars()
+                        .next_back()
+                        .is_none_or(char::is_whitespace)
+            })
+            .map(|(offset, _)| offset)
+            .unwrap_or(text.len());
+
+        let mut chars = text[..comment].char_indices().peekable();
+        while let Some((offset, c)) = chars.next() {
+            let kind = match c {
+                '=' => TokenKind::Equals,
+                ',' => TokenKind::Comma,
+                '[' => TokenKind::LBracket,
+                ']' => TokenKind::RBracket,
+                c if c.is_whitespace() => continue,
+                _ => TokenKind::Word,
+            };
+
+            let mut end = offset + c.len_utf8();
+            if kind == TokenKind::Word {
+                while let Some(&(next_offset, next)) = chars.peek() {
+                    if next.is_whitespace() || "=,[]".contains(next) {
+                        break;
+                    }
+
+                    end = next_offset + next.len_utf8();
+                    chars.next();
+                }
+            }
+
+            tokens.push(Token {
+                kind,
+                text: &text[offset..end],
+                offset,
+            });
+        }
+
+        Line {
+            n,
+            text,
+            tokens,
+            comment,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    /* The line as written without its comment */
+    pub fn content(&self) -> &'a str {
+        &self.text[..self.comment]
+    }
+
+    /* The line as written from `token` on, for values that can have whitespace in them */
+    pub fn rest_from(&self, token: &Token) -> &'a str {
+        self.text[token.offset..self.comment].trim_end()
+    }
+
+    /* An error about the part of the line starting at byte `offset` and `len` bytes long */
+    fn error_span(&self, offset: usize, len: usize, msg: &str) -> amc::Error {
+        amc::Error::config(
+            self.n,
+            self.text[..offset].chars().count() + 1,
+            std::cmp::max(self.text[offset..offset + len].chars().count(), 1),
+            self.text,
+            msg,
+        )
+    }
+
+    /* An error about `part`, which has to be a slice of the line's text */
+    pub fn error_in(&self, part: &str, msg: &str) -> amc::Error {
+        self.error_span(
+            part.as_ptr() as usize - self.text.as_ptr() as usize,
+            part.len(),
+            msg,
+        )
+    }
+
+    pub fn error_at(&self, token: &Token, msg: &str) -> amc::Error {
+        self.error_span(token.offset, token.text.len(), msg)
+    }
+
+    /* An error about everything from `first` to `last`, both included */
+    pub fn error_between(&self, first: &Token, last: &Token, msg: &str) -> amc::Error {
+        self.error_span(
+            first.offset,
+            last.offset + last.text.len() - first.offset,
+            msg,
+        )
+    }
+
+    /* An error about the whole line, without the surrounding whitespace */
+    pub fn error(&self, msg: &str) -> amc::Error {
+        let start = self.text.len() - self.text.trim_start().len();
+
+        self.error_span(start, self.content().trim().len(), msg)
+    }
+
+    /* An error about something missing at the end of the line */
+    pub fn error_at_end(&self, msg: &str) -> amc::Error {
+        self.error_span(self.content().trim_end().len(), 0, msg)
+    }
+}
+
+/* The lines of `content`, numbered from `first_line` on */
+pub fn tokenize(content: &str, first_line: usize) -> Vec<Line<'_>> {
+    content
+        .lines()
+        .enumerate()
+        .map(|(i, text)| Line::tokenize(first_line + i, text))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts<'a>(line: &Line<'a>) -> Vec<&'a str> {
+        line.tokens.iter().map(|token| token.text).collect()
+    }
+
+    /* The line, column and length of a config error */
+    fn span(err: &amc::Error) -> (usize, usize, usize) {
+        match err {
+            amc::Error::Config {
+                line,
+                column,
+                length,
+                ..
+            } => (*line, *column, *length),
+            err => panic!("Not a config error: {}", err),
+        }
+    }
+
+    #[test]
+    fn comments_start_after_whitespace() {
+        let lines = tokenize("# whole line\n  # indented\nlaptop = off # turned off\n", 1);
+
+        assert!(lines[0].is_empty());
+        assert!(lines[1].is_empty());
+        assert_eq!(texts(&lines[2]), vec!["laptop", "=", "off"]);
+        assert_eq!(lines[2].content(), "laptop = off ");
+        assert_eq!(lines[2].n, 3);
+    }
+
+    #[test]
+    fn comments_dont_start_inside_words() {
+        let lines = tokenize("*background: #1d1f21\nhook = a#b\n", 1);
+
+        assert_eq!(lines[0].content(), "*background: ");
+        assert_eq!(texts(&lines[1]), vec!["hook", "=", "a#b"]);
+        assert_eq!(lines[1].content(), "hook = a#b");
+    }
+
+    #[test]
+    fn tokens_split_on_punctuation() {
+        let lines = tokenize("[setup desk]\n409e9d21 = 0x0, 90, mode=1920x1080", 1);
+
+        assert_eq!(texts(&lines[0]), vec!["[", "setup", "desk", "]"]);
+        assert_eq!(
+            texts(&lines[1]),
+            vec![
+                "409e9d21",
+                "=",
+                "0x0",
+                ",",
+                "90",
+                ",",
+                "mode",
+                "=",
+                "1920x1080"
+            ]
+        );
+        assert!(lines[1].tokens[1].is(TokenKind::Equals));
+        assert_eq!(
+            lines[1].rest_from(&lines[1].tokens[2]),
+            "0x0, 90, mode=1920x1080"
+        );
+    }
+
+    #[test]
+    fn errors_count_characters_not_bytes() {
+        let lines = tokenize("écran = 0x0, 9°", 7);
+        let line = &lines[0];
+
+        assert_eq!(span(&line.error_at(&line.tokens[0], "")), (7, 1, 5));
+        assert_eq!(span(&line.error_at(&line.tokens[4], "")), (7, 14, 2));
+        assert_eq!(
+            span(&line.error_between(&line.tokens[2], &line.tokens[4], "")),
+            (7, 9, 7)
+        );
+        assert_eq!(span(&line.error_at_end("")), (7, 16, 1));
+        assert_eq!(span(&line.error("")), (7, 1, 15));
+    }
+
+    #[test]
+    fn line_errors_skip_whitespace_and_comments() {
+        let lines = tokenize("  ünknown  # ünknown", 1);
+
+        assert_eq!(span(&lines[0].error("")), (1, 3, 7));
+        assert_eq!(span(&lines[0].error_at_end("")), (1, 10, 1));
+        assert_eq!(
+            span(&lines[0].error_in(&lines[0].text[2..4], "")),
+            (1, 3, 1)
+        );
+    }
+}
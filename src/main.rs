@@ -1,32 +1,29 @@
 // Copyright Olaru Alexandru
 // Licensed under the MIT license
 
+// Returns are spelled out at the end of functions too
+#![allow(clippy::needless_return)]
+
+use config::{
+    amc_config_dir_stamp, amc_parse_setup, amc_read_config_from_dir, amc_valid_setup_name,
+    AmcConfig, AmcFallback, AmcGlobals, MonitorSetup,
+};
 use core::time;
-use std::{env, process::exit};
-use xcb::randr;
+use std::{collections::HashMap, env, process::exit, time::Instant};
+use xcb::{randr, Xid};
 
 #[macro_use]
 mod amc;
+mod config;
+mod ctl;
+mod json;
+mod lexer;
 
-pub struct DeferCall<DeferFunc: FnMut()> {
-    defer_func: DeferFunc,
-}
-
-impl<DeferFunc: FnMut()> Drop for DeferCall<DeferFunc> {
-    fn drop(&mut self) {
-        (self.defer_func)();
-    }
-}
-
-macro_rules! defer {
-    ($e:expr) => {
-        let _defer_call = DeferCall {
-            defer_func: || -> () {
-                $e;
-            },
-        };
-    };
-}
+/* I can't get the XRRScreenChangeNotify event to fire (if that's even the right one),
+so polling it is. This is the default, configs can pick another one with poll-interval. */
+const POLL_INTERVAL: time::Duration = time::Duration::from_secs(3);
+const RECONNECT_BACKOFF_MIN: time::Duration = time::Duration::from_secs(1);
+const RECONNECT_BACKOFF_MAX: time::Duration = time::Duration::from_secs(30);
 
 macro_rules! println_error {
     ($msg:expr) => {{
@@ -41,38 +38,25 @@ macro_rules! die {
     }};
 }
 
-#[derive(Debug)]
-pub struct MonitorSetup {
-    configs: Vec<amc::MonitorConfig>,
-}
-
-fn amc_apply_best_setup_for_mons(
+/* Every setup whose monitors are all connected, those with the highest priority and then those
+covering the most monitors first */
+fn amc_find_matching_setups_for_mons<'a>(
     xstack: &amc::XCBStack,
-    mons: &Vec<amc::Monitor>,
-    mon_setups: &Vec<MonitorSetup>,
-) -> amc::Result<()> {
-    let mut configs_changed = false;
-
-    let mut screen_w = 0;
-    let mut screen_h = 0;
-    /* These two variables fucking anger me; upset me on a personal level even.
-    Why does randr NEED to know the screen w and h in MILLIMETERS. What
-    happens if I have empty gaps in between the monitors, how do I calculate these
-    values?? What is the canonical size IN FUCKING MILLIMETERS of an arbitrarily sized
-    undisplayable region of pixels? I will not even bother looking at the xrandr source
-    to see what bullshit they came up with because I know it will only add to my
-    growing hatred for the dumpsterfire that is X11 */
-    let mut screen_w_mm = 0;
-    let mut screen_h_mm = 0;
-
-    let mut monitors_hit_in_setup = 0;
-    let mut best_setup: Option<&MonitorSetup> = None;
+    mons: &[amc::Monitor],
+    mon_setups: &'a [MonitorSetup],
+) -> Vec<&'a MonitorSetup> {
+    let mut matching = Vec::<&MonitorSetup>::new();
+    let host = amc::hostname();
 
     for setup in mon_setups {
+        if setup.out_of_scope(xstack, host.as_deref(), mons).is_some() {
+            continue;
+        }
+
         match setup
             .configs
             .iter()
-            .map(|conf| mons.iter().find(|mon| mon.id == conf.id).is_some())
+            .map(|conf| mons.iter().any(|mon| mon.id == conf.id))
             .reduce(|acc, e| acc && e)
         {
             Some(true) => (),
@@ -80,303 +64,2075 @@ fn amc_apply_best_setup_for_mons(
             None => continue,
         };
 
-        if setup.configs.len() > monitors_hit_in_setup {
-            monitors_hit_in_setup = setup.configs.len();
-            best_setup = Some(setup);
-        }
-    }
+        matching.push(setup);
+    }
+
+    // Stable, so setups hitting the same number of monitors keep their order
+    matching.sort_by_key(|setup| std::cmp::Reverse((setup.priority, setup.configs.len())));
+
+    matching
+}
+
+/* What applying a setup comes down to, worked out without touching anything */
+struct AmcLayoutPlan {
+    /* Where each monitor ends up, once the layout is shifted to start at 0x0 */
+    placements: Vec<amc::MonitorConfig>,
+    /* Only the crtcs that need changing */
+    crtc_configs: Vec<amc::CrtcConfig>,
+    screen: amc::ScreenSize,
+    /* The output the setup wants as the randr primary one, if any */
+    primary: Option<randr::Output>,
+}
+
+/* Monitors placed next to each other from left to right, in their current order and rotation.
+Monitors that are off go after the others. */
+fn amc_extended_configs(mons: &[amc::Monitor]) -> amc::Result<Vec<amc::MonitorConfig>> {
+    let mut ordered_mons = mons.iter().collect::<Vec<&amc::Monitor>>();
+    ordered_mons.sort_by_key(|mon| match &mon.crtc_config {
+        Some(crtc_config) => (0, crtc_config.x, crtc_config.y),
+        None => (1, 0, 0),
+    });
+
+    let mut configs = Vec::<amc::MonitorConfig>::with_capacity(mons.len());
+    let mut x: i32 = 0;
+    for mon in ordered_mons {
+        let rot = match &mon.crtc_config {
+            Some(crtc_config) => crtc_config.rot,
+            None => randr::Rotation::ROTATE_0,
+        };
+
+        let x_pos = match i16::try_from(x) {
+            Ok(x) => x,
+            Err(_) => {
+                return Err("The monitors are too wide to be placed next to each other".into())
+            }
+        };
+
+        let conf = amc::MonitorConfig::new(mon.id, x_pos, 0, rot);
+        x += conf.extent(mon.mode_for(&conf)?).0 as i32;
+        configs.push(conf);
+    }
+
+    Ok(configs)
+}
+
+/* Plans `setup` for the connected monitors, or the `fallback` config if there is no setup.
+When `force` is set every crtc gets reconfigured, even those already matching the setup. */
+fn amc_plan_setup_for_mons(
+    xstack: &amc::XCBStack,
+    mons: &[amc::Monitor],
+    setup: Option<&MonitorSetup>,
+    fallback: AmcFallback,
+    force: bool,
+) -> amc::Result<AmcLayoutPlan> {
+    let configs = match (setup, fallback) {
+        (Some(setup), _) => setup.configs.clone(),
+        /* If no setup matched what's connected, we mirror each display. Those that can
+         * share a crtc get to do so further down.
+         */
+        (None, AmcFallback::Mirror) => mons
+            .iter()
+            .map(|mon| amc::MonitorConfig::new(mon.id, 0, 0, randr::Rotation::ROTATE_0))
+            .collect(),
+        (None, AmcFallback::Extend) => amc_extended_configs(mons)?,
+        (None, AmcFallback::Keep) => {
+            return Ok(AmcLayoutPlan {
+                placements: Vec::new(),
+                crtc_configs: Vec::new(),
+                screen: amc::ScreenSize::get(xstack)?,
+                primary: None,
+            })
+        }
+    };
+    let setup_name = setup.map(|setup| setup.name.as_str()).unwrap_or("default");
+
+    // Each monitor that ends up on the screen, along with where it goes and in which mode
+    let mut placements = Vec::<(&amc::Monitor, amc::MonitorConfig, &amc::MonitorMode)>::new();
+    // Connected monitors the setup turns off
+    let mut off_mons = Vec::<&amc::Monitor>::new();
+    let mut primary = None;
+
+    let mut screen_w: i32 = 0;
+    let mut screen_h: i32 = 0;
+    /* Randr also NEEDS to know the screen w and h in millimeters, whatever that means for a
+    bunch of monitors with gaps in between them. We go through the monitors that end up on
+    the screen and let the setup's dpi policy make something up from them. */
+    let mut placed_mons = Vec::<&amc::Monitor>::new();
+
+    /* Monitors can be placed left of or above the origin, but X wants the screen to start
+    at 0x0 so the whole layout gets shifted for its top left corner to end up there */
+    let min_x = configs
+        .iter()
+        .filter(|conf| !conf.off)
+        .map(|conf| conf.x as i32)
+        .min()
+        .unwrap_or(0);
+    let min_y = configs
+        .iter()
+        .filter(|conf| !conf.off)
+        .map(|conf| conf.y as i32)
+        .min()
+        .unwrap_or(0);
+
+    for conf in &configs {
+        let mon = match mons.iter().find(|mon| mon.id == conf.id) {
+            Some(x) => x,
+            None => {
+                return Err(amc::Error::Resources(
+                    format_args!(
+                        "Monitor {:x} from setup '{}' is not connected",
+                        conf.id, setup_name
+                    )
+                    .to_string(),
+                ))
+            }
+        };
+
+        if conf.off {
+            off_mons.push(mon);
+            continue;
+        }
+
+        if conf.primary {
+            primary = Some(mon.output);
+        }
+
+        let mode = mon.mode_for(conf)?;
+
+        let (x, y) = match (
+            i16::try_from(conf.x as i32 - min_x),
+            i16::try_from(conf.y as i32 - min_y),
+        ) {
+            (Ok(x), Ok(y)) => (x, y),
+            _ => {
+                return Err(amc::Error::Resources(
+                    format_args!(
+                        "Setup '{}' is too large, monitors can't be further than {} pixels apart",
+                        setup_name,
+                        i16::MAX
+                    )
+                    .to_string(),
+                ))
+            }
+        };
+
+        let conf = amc::MonitorConfig {
+            x,
+            y,
+            ..conf.clone()
+        };
+
+        let (w, h) = conf.extent(mode);
+        screen_w = std::cmp::max(screen_w, (x as i32) + (w as i32));
+        screen_h = std::cmp::max(screen_h, (y as i32) + (h as i32));
+
+        placements.push((mon, conf, mode));
+        placed_mons.push(mon);
+    }
+
+    /* Monitors showing the same part of the screen in the same mode share a crtc when the
+    hardware lets them, leaving the other crtcs for other monitors. Each head is a group of
+    such monitors (indices into placements). */
+    let mut heads = Vec::<Vec<usize>>::new();
+    for (i, (mon, conf, mode)) in placements.iter().enumerate() {
+        match heads.iter_mut().find(|head| {
+            let head_conf = &placements[head[0]].1;
+
+            head_conf.x == conf.x
+                && head_conf.y == conf.y
+                && head_conf.rot == conf.rot
+                && head_conf.scale == conf.scale
+                && head.iter().all(|&j| {
+                    let (other, _, other_mode) = &placements[j];
+                    other.can_share_crtc_with(other_mode.mode, mon, mode.mode)
+                })
+        }) {
+            Some(head) => head.push(i),
+            None => heads.push(vec![i]),
+        }
+    }
+
+    // Crtcs are only handed out now, so that monitors left out of the setup don't take any
+    let crtcs = amc::assign_crtcs(
+        xstack,
+        &heads
+            .iter()
+            .map(|head| head.iter().map(|&i| placements[i].0).collect())
+            .collect::<Vec<Vec<&amc::Monitor>>>(),
+        &off_mons,
+    )?;
+
+    let mut crtc_configs = heads
+        .iter()
+        .zip(&crtcs)
+        .filter_map(|(head, &crtc)| {
+            let outputs = head
+                .iter()
+                .map(|&i| placements[i].0.output)
+                .collect::<Vec<randr::Output>>();
+
+            // The crtc needs configuring as soon as one of its monitors isn't how we want it
+            head.iter().find_map(|&i| {
+                let (mon, conf, mode) = &placements[i];
+                mon.crtc_config_for(conf, mode, crtc, &outputs, force)
+            })
+        })
+        .collect::<Vec<amc::CrtcConfig>>();
+
+    // Monitors turned off by the setup, unless their crtc went to another monitor
+    for mon in &off_mons {
+        if let Some(crtc) = mon.crtc.filter(|crtc| !crtcs.contains(crtc)) {
+            if !crtc_configs.iter().any(|conf| conf.crtc == crtc) {
+                crtc_configs.push(amc::CrtcConfig::disabled(crtc));
+            }
+        }
+    }
+
+    // The primary output goes first, or the first monitor of the setup if it isn't placed
+    let primary_output = match primary {
+        Some(output) => output,
+        None => amc::get_primary_output(xstack)?,
+    };
+    if let Some(i) = placed_mons
+        .iter()
+        .position(|mon| mon.output == primary_output)
+    {
+        let primary_mon = placed_mons.remove(i);
+        placed_mons.insert(0, primary_mon);
+    }
+
+    let dpi = match setup {
+        Some(setup) => setup.dpi,
+        None => amc::DpiPolicy::Primary,
+    }
+    .dpi(&placed_mons);
+
+    let (screen_w, screen_h) = match (u16::try_from(screen_w), u16::try_from(screen_h)) {
+        (Ok(w), Ok(h)) => (w, h),
+        _ => {
+            return Err(format_args!(
+                "The layout needs a {}x{} screen, which is more than X can handle",
+                screen_w, screen_h
+            )
+            .to_string()
+            .into())
+        }
+    };
+
+    Ok(AmcLayoutPlan {
+        placements: placements.into_iter().map(|(_, conf, _)| conf).collect(),
+        crtc_configs,
+        screen: amc::ScreenSize::from_dpi(screen_w, screen_h, dpi),
+        primary,
+    })
+}
+
+/* Applies `setup` to the connected monitors, see amc_plan_setup_for_mons. Returns whether
+anything changed. */
+fn amc_apply_setup_for_mons(
+    xstack: &amc::XCBStack,
+    mons: &[amc::Monitor],
+    setup: Option<&MonitorSetup>,
+    fallback: AmcFallback,
+    force: bool,
+) -> amc::Result<bool> {
+    let plan = amc_plan_setup_for_mons(xstack, mons, setup, fallback, force)?;
+
+    // Even if no crtc needs changing the screen might, e.g. after unplugging a monitor
+    let mut changed = amc::apply_layout(xstack, &plan.crtc_configs, &plan.screen)?;
+
+    if let Some(output) = plan.primary {
+        if amc::get_primary_output(xstack)? != output {
+            amc::set_primary_output(xstack, output)?;
+            changed = true;
+        }
+    }
+
+    if let Some(setup) = setup {
+        amc::merge_xresources(xstack, &setup.xresources)?;
+    }
+
+    Ok(changed)
+}
+
+/* Runs the user's hook after a layout got applied, without waiting for it to finish. What was
+applied is passed in the environment. */
+fn amc_run_hook(
+    hook: &str,
+    xstack: &amc::XCBStack,
+    setup_name: Option<&str>,
+    mons: &[amc::Monitor],
+) {
+    let child = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(hook)
+        .env("AMC_SETUP", setup_name.unwrap_or(""))
+        .env("AMC_DISPLAY", xstack.name())
+        .env(
+            "AMC_MONITORS",
+            mons.iter()
+                .map(|mon| format_args!("{:x}", mon.id).to_string())
+                .collect::<Vec<String>>()
+                .join(" "),
+        )
+        .spawn();
+
+    match child {
+        // Someone has to reap it
+        Ok(mut child) => {
+            std::thread::spawn(move || child.wait());
+        }
+        Err(err) => println_error!(format_args!("Could not run hook '{}'\n  {}", hook, err)),
+    }
+}
+
+/* Applies the setup called `name`, refusing to do so if any of its monitors aren't connected.
+Also returns whether anything changed. */
+fn amc_apply_named_setup_for_mons<'a>(
+    xstack: &amc::XCBStack,
+    mons: &[amc::Monitor],
+    mon_setups: &'a [MonitorSetup],
+    name: &str,
+) -> amc::Result<(&'a MonitorSetup, bool)> {
+    let setup = match mon_setups.iter().find(|setup| setup.name == name) {
+        Some(x) => x,
+        None => return Err(format_args!("No setup named '{}'", name).to_string().into()),
+    };
+
+    if let Some(scope) = setup.out_of_scope(xstack, amc::hostname().as_deref(), mons) {
+        return Err(format_args!("Setup '{}' is not meant for {}", name, scope)
+            .to_string()
+            .into());
+    }
+
+    let missing = setup
+        .configs
+        .iter()
+        .filter(|conf| !mons.iter().any(|mon| mon.id == conf.id))
+        .map(|conf| format_args!("{:x}", conf.id).to_string())
+        .collect::<Vec<String>>();
+
+    if !missing.is_empty() {
+        return Err(format_args!(
+            "Setup '{}' can't be applied, the following monitors are not connected: {}\n{}",
+            name,
+            missing.join(", "),
+            amc_format_monitors(mons).trim_end()
+        )
+        .to_string()
+        .into());
+    }
+
+    // There's a setup, so the fallback doesn't matter
+    let changed = amc_apply_setup_for_mons(xstack, mons, Some(setup), AmcFallback::Keep, false)?;
+
+    Ok((setup, changed))
+}
+
+/* The ids of the given monitors in a stable order, used to tell if what's connected changed */
+fn amc_mon_ids(mons: &[amc::Monitor]) -> Vec<u32> {
+    let mut ids = mons.iter().map(|mon| mon.id).collect::<Vec<u32>>();
+    ids.sort();
+    ids
+}
+
+/* Reflection bits are ignored, see amc_reflection */
+fn amc_rotation_degrees(rot: randr::Rotation) -> u16 {
+    if rot.contains(randr::Rotation::ROTATE_90) {
+        90
+    } else if rot.contains(randr::Rotation::ROTATE_180) {
+        180
+    } else if rot.contains(randr::Rotation::ROTATE_270) {
+        270
+    } else {
+        0
+    }
+}
+
+/* How a monitor is mirrored as written in configs, None if it isn't */
+fn amc_reflection(rot: randr::Rotation) -> Option<&'static str> {
+    match (
+        rot.contains(randr::Rotation::REFLECT_X),
+        rot.contains(randr::Rotation::REFLECT_Y),
+    ) {
+        (true, true) => Some("xy"),
+        (true, false) => Some("x"),
+        (false, true) => Some("y"),
+        (false, false) => None,
+    }
+}
+
+/* The inverse of amc_parse_setup */
+fn amc_format_setup_conf(configs: &[amc::MonitorConfig]) -> String {
+    let mut out = String::new();
+
+    for conf in configs {
+        if conf.off {
+            out += &format_args!("{:x} = off\n", conf.id).to_string();
+            continue;
+        }
+
+        out += &format_args!(
+            "{:x} = {}x{}, {}",
+            conf.id,
+            conf.x,
+            conf.y,
+            amc_rotation_degrees(conf.rot)
+        )
+        .to_string();
+
+        if let Some(reflect) = amc_reflection(conf.rot) {
+            out += &format_args!(", reflect={}", reflect).to_string();
+        }
+        if let Some((w, h)) = conf.mode {
+            out += &format_args!(", mode={}x{}", w, h).to_string();
+        }
+        if let Some(rate) = conf.rate {
+            out += &format_args!(", rate={}", (rate * 100.0).round() / 100.0).to_string();
+        }
+        if conf.scale != 1.0 {
+            out += &format_args!(", scale={}", conf.scale).to_string();
+        }
+        if conf.primary {
+            out += ", primary";
+        }
+        out.push('\n');
+    }
+
+    out
+}
+
+/* Writes a setup for the connected monitors to `<config_dir>/<name>.conf`, laid out left to
+right in their current order, as a starting point for the user to edit */
+fn amc_init_setup(config_dir: &str, name: &str, mons: &[amc::Monitor]) -> amc::Result<String> {
+    if !amc_valid_setup_name(name) || name.starts_with('.') {
+        return Err(format_args!("Invalid setup name '{}'", name)
+            .to_string()
+            .into());
+    }
+
+    if mons.is_empty() {
+        return Err("No connected monitors to make a setup from".into());
+    }
+
+    let mut out = format_args!(
+        "# Setup '{}', made by 'amc init' from the monitors that were connected at the time.\n\
+         # The monitors are placed next to each other from left to right, edit the positions\n\
+         # (and rotations) to match your desk. Run 'amc --help' for everything a setup can have.\n\
+         version = 2\n\
+         \n\
+         [setup {}]\n",
+        name, name
+    )
+    .to_string();
+
+    for conf in amc_extended_configs(mons)? {
+        let mon = mons.iter().find(|mon| mon.id == conf.id).unwrap();
+
+        out += &format_args!(
+            "\n# {}: {}, {}x{}\n",
+            mon.name,
+            match (&mon.edid, mon.edid_info()) {
+                (None, _) => "no EDID, identified by connector and modes".to_string(),
+                (Some(_), Some(info)) => match info.model {
+                    Some(model) => format_args!("{} {}", info.vendor, model).to_string(),
+                    None => format_args!("{} product {:04x}", info.vendor, info.product_code)
+                        .to_string(),
+                },
+                (Some(_), None) => "unreadable EDID".to_string(),
+            },
+            mon.w,
+            mon.h
+        )
+        .to_string();
+        out += &amc_format_setup_conf(&[conf]);
+    }
+
+    if let Err(err) = std::fs::create_dir_all(config_dir) {
+        return Err(
+            format_args!("Could not create config dir '{}'\n  {}", config_dir, err)
+                .to_string()
+                .into(),
+        );
+    }
+
+    /* Any file can define the setup, in a section or as a file in the original format, and a
+    second definition would make the whole config dir unusable */
+    let config = match amc_read_config_from_dir(config_dir) {
+        Ok(config) => config,
+        Err(err) => {
+            return Err(format_args!(
+                "{}\nThe config dir has to be fixed before adding a setup to it",
+                err
+            )
+            .to_string()
+            .into())
+        }
+    };
+    if config.setups.iter().any(|setup| setup.name == name) {
+        return Err(format_args!(
+            "Setup '{}' is already defined in the config dir, not adding another one",
+            name
+        )
+        .to_string()
+        .into());
+    }
+
+    // create_new, so that an existing setup never gets overwritten
+    let path = format_args!("{}/{}.conf", config_dir, name).to_string();
+    let written = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .and_then(|mut file| std::io::Write::write_all(&mut file, out.as_bytes()));
+
+    match written {
+        Ok(()) => Ok(format_args!("Wrote setup '{}' to '{}'\n", name, path).to_string()),
+        Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => Err(format_args!(
+            "Setup file '{}' already exists, not overwriting it",
+            path
+        )
+        .to_string()
+        .into()),
+        Err(err) => Err(format_args!("Could not write '{}'\n  {}", path, err)
+            .to_string()
+            .into()),
+    }
+}
+
+/* The last applied or manually saved layout for each set of connected monitors, persisted
+to a state file so that it survives restarts. The file is made of sections, one per set of
+monitors, each holding that layout in the same format as a config file:
+    [<monitor id>,<monitor id>,...]
+    <monitor id> = <x>x<y>, <rotation degrees>
+*/
+struct LayoutState {
+    path: String,
+    /* Named after the monitor ids they were saved for, see LayoutState::key */
+    layouts: Vec<MonitorSetup>,
+    /* Set when the file couldn't be read. It's moved aside to '<path>.bad' rather than
+    overwritten the next time a layout gets remembered, so the layouts in it can be recovered. */
+    unreadable: bool,
+}
+
+impl LayoutState {
+    fn default_path() -> String {
+        match env::var("XDG_STATE_HOME") {
+            Ok(xdg_state_home) => format_args!("{}/amc/layouts", xdg_state_home).to_string(),
+            Err(_) => match env::var("HOME") {
+                Ok(home) => format_args!("{}/.local/state/amc/layouts", home).to_string(),
+                Err(_) => panic!("Couldn't get user's state dir"),
+            },
+        }
+    }
+
+    fn key(mon_ids: &[u32]) -> String {
+        mon_ids
+            .iter()
+            .map(|id| format_args!("{:x}", id).to_string())
+            .collect::<Vec<String>>()
+            .join(",")
+    }
+
+    fn load(path: &str) -> amc::Result<LayoutState> {
+        let mut state = LayoutState {
+            path: path.to_string(),
+            layouts: Vec::new(),
+            unreadable: false,
+        };
+
+        let file_content = match std::fs::read_to_string(path) {
+            Ok(x) => x,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(state),
+            Err(err) => {
+                return Err(
+                    format_args!("Could not read state file '{}'\n  {}", path, err)
+                        .to_string()
+                        .into(),
+                )
+            }
+        };
+
+        let lines = lexer::tokenize(&file_content, 1);
+        let mut errors = Vec::<amc::Error>::new();
+
+        // Keys have commas in them, so headers are found as written rather than from the tokens
+        let headers = (0..lines.len())
+            .filter_map(|i| {
+                lines[i]
+                    .text
+                    .trim()
+                    .strip_prefix('[')
+                    .and_then(|line| line.strip_suffix(']'))
+                    .map(|key| (i, key))
+            })
+            .collect::<Vec<(usize, &str)>>();
+
+        // Comments before the first section are skipped
+        for (k, &(header, key)) in headers.iter().enumerate() {
+            let end = headers.get(k + 1).map(|x| x.0).unwrap_or(lines.len());
+
+            state.layouts.push(amc_parse_setup(
+                key,
+                &lines[header + 1..end],
+                &[],
+                None,
+                &mut errors,
+            ));
+        }
+
+        if !errors.is_empty() {
+            return Err(amc::Error::many(errors).in_file(path));
+        }
+
+        Ok(state)
+    }
+
+    fn get(&self, mon_ids: &[u32]) -> Option<&MonitorSetup> {
+        let key = Self::key(mon_ids);
+
+        self.layouts.iter().find(|layout| layout.name == key)
+    }
+
+    /* Stores `configs` as the layout for `mon_ids`, only touching the file if anything changed */
+    fn remember(&mut self, mon_ids: &[u32], configs: &[amc::MonitorConfig]) -> amc::Result<()> {
+        let key = Self::key(mon_ids);
+
+        match self.layouts.iter_mut().find(|layout| layout.name == key) {
+            Some(layout) if layout.configs == configs => return Ok(()),
+            Some(layout) => layout.configs = configs.to_vec(),
+            None => self.layouts.push(MonitorSetup {
+                name: key,
+                priority: 0,
+                configs: configs.to_vec(),
+                dpi: amc::DpiPolicy::Primary,
+                xresources: Vec::new(),
+                display: None,
+                screen: None,
+                host: None,
+                panels: None,
+                aliases: Vec::new(),
+                extends: None,
+            }),
+        }
+
+        let mut out =
+            "# Written by amc, the last layout used for each set of monitors\n".to_string();
+        for layout in &self.layouts {
+            out += &format_args!("\n[{}]\n", layout.name).to_string();
+            out += &amc_format_setup_conf(&layout.configs);
+        }
+
+        if let Some(dir) = std::path::Path::new(&self.path).parent() {
+            if let Err(err) = std::fs::create_dir_all(dir) {
+                return Err(format_args!(
+                    "Could not create state dir '{}'\n  {}",
+                    dir.display(),
+                    err
+                )
+                .to_string()
+                .into());
+            }
+        }
+
+        if self.unreadable {
+            let bad_path = format_args!("{}.bad", self.path).to_string();
+
+            if let Err(err) = std::fs::rename(&self.path, &bad_path) {
+                return Err(format_args!(
+                    "Could not move the unreadable state file '{}' to '{}', not saving layouts\n  {}",
+                    self.path, bad_path, err
+                )
+                .to_string()
+                .into());
+            }
+            self.unreadable = false;
+        }
+
+        if let Err(err) = std::fs::write(&self.path, out) {
+            return Err(
+                format_args!("Could not write state file '{}'\n  {}", self.path, err)
+                    .to_string()
+                    .into(),
+            );
+        }
+
+        Ok(())
+    }
+
+    /* Remembers how the connected monitors are currently laid out */
+    fn save_current(&mut self, mons: &[amc::Monitor]) -> amc::Result<String> {
+        // Monitors that are off stay that way, the others keep their mode if it isn't the best
+        let configs = mons
+            .iter()
+            .map(|mon| match &mon.crtc_config {
+                Some(crtc_config) => {
+                    let mode = mon
+                        .find_mode(crtc_config.mode)
+                        .filter(|mode| mode.mode != mon.mode_best);
+
+                    amc::MonitorConfig {
+                        mode: mode.map(|mode| (mode.width, mode.height)),
+                        rate: mode.map(|mode| mode.refresh),
+                        scale: crtc_config.scale,
+                        ..amc::MonitorConfig::new(
+                            mon.id,
+                            crtc_config.x,
+                            crtc_config.y,
+                            crtc_config.rot,
+                        )
+                    }
+                }
+                None => amc::MonitorConfig {
+                    off: true,
+                    ..amc::MonitorConfig::new(mon.id, 0, 0, randr::Rotation::ROTATE_0)
+                },
+            })
+            .collect::<Vec<amc::MonitorConfig>>();
+
+        if configs.iter().all(|conf| conf.off) {
+            return Err("None of the connected monitors is configured, nothing to save".into());
+        }
+
+        self.remember(&amc_mon_ids(mons), &configs)?;
+
+        Ok(format_args!(
+            "Saved the layout of {} monitor(s)\n",
+            configs.iter().filter(|conf| !conf.off).count()
+        )
+        .to_string())
+    }
+}
+
+/* State kept for each X screen between polls */
+struct AmcScreen {
+    xstack: amc::XCBStack,
+    /* The monitors that were connected when the current layout got applied, None if it needs
+    to be applied again on the next poll */
+    applied_mon_ids: Option<Vec<u32>>,
+    /* Name of the last applied setup, None if the default config is in use */
+    active_setup: Option<String>,
+    /* Setup forced with 'apply <setup> pin' along with the monitors that were connected back then */
+    pinned_setup: Option<(Vec<u32>, String)>,
+    /* Setup picked with 'next'/'prev' for each set of connected monitors (see amc_mon_ids) */
+    chosen_setups: HashMap<Vec<u32>, String>,
+    /* Whether the layout in use was restored from the remembered layouts for lack of a
+    matching setup */
+    layout_restored: bool,
+}
+
+impl AmcScreen {
+    fn new(xstack: amc::XCBStack) -> AmcScreen {
+        AmcScreen {
+            xstack,
+            applied_mon_ids: None,
+            active_setup: None,
+            pinned_setup: None,
+            chosen_setups: HashMap::new(),
+            layout_restored: false,
+        }
+    }
+
+    /* Picks the setup to use for `mons`: the pinned one, then the one chosen with 'next'/'prev'
+    and finally the best matching one */
+    fn select_setup(&mut self, mons: &[amc::Monitor], setups: &[MonitorSetup]) -> Option<String> {
+        let mon_ids = amc_mon_ids(mons);
+
+        if let Some((pinned_ids, name)) = &self.pinned_setup {
+            // Either the monitors changed or the setup is gone after a reload
+            if *pinned_ids != mon_ids || !setups.iter().any(|setup| setup.name == *name) {
+                self.pinned_setup = None;
+            }
+        }
+
+        let matching = amc_find_matching_setups_for_mons(&self.xstack, mons, setups);
+
+        let wanted = match &self.pinned_setup {
+            Some((_, name)) => Some(name),
+            None => self.chosen_setups.get(&mon_ids),
+        };
+
+        if let Some(name) = wanted {
+            if let Some(setup) = setups.iter().find(|setup| setup.name == *name) {
+                // A pinned setup doesn't need to be the best match, but a chosen one must still match
+                if self.pinned_setup.is_some() || matching.iter().any(|m| m.name == *name) {
+                    return Some(setup.name.clone());
+                }
+            }
+        }
+
+        matching.first().map(|setup| setup.name.clone())
+    }
+
+    /* Unless enforcing, the layout only gets applied when the connected monitors or the config
+    change, so that we don't fight whatever the user tweaked by hand in the meantime */
+    fn poll(
+        &mut self,
+        config: &AmcConfig,
+        layouts: &mut LayoutState,
+        enforce: bool,
+        force: bool,
+    ) -> amc::Result<()> {
+        let setups = &config.setups;
+        let mut mons = amc::Monitor::get_all_connected(&self.xstack)?;
+
+        let mon_ids = amc_mon_ids(&mons);
+
+        // Reported once for each change of the connected monitors, they don't go away by polling
+        if self.applied_mon_ids.as_ref() != Some(&mon_ids) {
+            for err in mons.iter_mut().filter_map(|mon| mon.edid_error.take()) {
+                println_error!(format_args!(
+                    "{}, identifying it by connector and modes",
+                    amc::Error::from(err)
+                ));
+            }
+        }
+
+        if !force && !enforce && self.applied_mon_ids.as_ref() == Some(&mon_ids) {
+            return Ok(());
+        }
+
+        let setup_name = self.select_setup(&mons, setups);
+
+        // Without a setup we fall back to the last layout used with these monitors, if any
+        let setup = match &setup_name {
+            Some(name) => setups.iter().find(|setup| setup.name == *name),
+            None => layouts.get(&mon_ids),
+        };
+        let restored = setup_name.is_none() && setup.is_some();
+
+        let changed = match amc_apply_setup_for_mons(
+            &self.xstack,
+            &mons,
+            setup,
+            config.globals.fallback,
+            force,
+        ) {
+            Ok(x) => x,
+            /* Retrying won't get us more crtcs, a larger screen or a mode a monitor doesn't
+            have, so we don't until the monitors change. Other errors (like the X server refusing a request while
+            monitors are being plugged in) are worth retrying on the next poll. */
+            Err(err @ amc::Error::Resources(_)) => {
+                self.applied_mon_ids = Some(mon_ids);
+                return Err(err);
+            }
+            Err(err) => return Err(err),
+        };
+
+        if let Some(hook) = config.globals.hook.as_ref().filter(|_| changed) {
+            amc_run_hook(hook, &self.xstack, setup_name.as_deref(), &mons);
+        }
+
+        let applied_configs = setup
+            .filter(|_| !restored)
+            .map(|setup| setup.configs.clone());
+
+        self.active_setup = setup_name;
+        self.layout_restored = restored;
+        self.applied_mon_ids = Some(mon_ids.clone());
+
+        if let Some(configs) = applied_configs {
+            layouts.remember(&mon_ids, &configs)?;
+        }
+
+        Ok(())
+    }
+
+    /* Applies the matching setup `step` positions away from the active one */
+    fn cycle(
+        &mut self,
+        mons: &[amc::Monitor],
+        config: &AmcConfig,
+        step: isize,
+    ) -> amc::Result<String> {
+        let matching = amc_find_matching_setups_for_mons(&self.xstack, mons, &config.setups);
+
+        if matching.is_empty() {
+            return Err("No setup matches the connected monitors".into());
+        }
+
+        /* Without a matching setup active (e.g. after restoring a layout or the fallback), 'next'
+        starts at the best match and 'prev' at the last one */
+        let cur = match matching
+            .iter()
+            .position(|setup| Some(&setup.name) == self.active_setup.as_ref())
+        {
+            Some(x) => x as isize,
+            None if step > 0 => -1,
+            None => matching.len() as isize,
+        };
+        let setup = matching[(cur + step).rem_euclid(matching.len() as isize) as usize];
+
+        // There's a setup, so the fallback doesn't matter
+        let changed =
+            amc_apply_setup_for_mons(&self.xstack, mons, Some(setup), AmcFallback::Keep, false)?;
+
+        if let Some(hook) = config.globals.hook.as_ref().filter(|_| changed) {
+            amc_run_hook(hook, &self.xstack, Some(&setup.name), mons);
+        }
+
+        self.active_setup = Some(setup.name.clone());
+        self.layout_restored = false;
+        self.applied_mon_ids = Some(amc_mon_ids(mons));
+        self.pinned_setup = None;
+        self.chosen_setups
+            .insert(amc_mon_ids(mons), setup.name.clone());
+
+        Ok(format_args!(
+            "Applied setup '{}' ({}/{})\n",
+            setup.name,
+            matching.iter().position(|m| m.name == setup.name).unwrap() + 1,
+            matching.len()
+        )
+        .to_string())
+    }
+
+    fn apply(
+        &mut self,
+        mons: &[amc::Monitor],
+        config: &AmcConfig,
+        layouts: &mut LayoutState,
+        name: &str,
+        pin: bool,
+    ) -> amc::Result<()> {
+        let (setup, changed) =
+            amc_apply_named_setup_for_mons(&self.xstack, mons, &config.setups, name)?;
+
+        if let Some(hook) = config.globals.hook.as_ref().filter(|_| changed) {
+            amc_run_hook(hook, &self.xstack, Some(name), mons);
+        }
+
+        self.active_setup = Some(setup.name.clone());
+        self.layout_restored = false;
+        self.applied_mon_ids = Some(amc_mon_ids(mons));
+        self.pinned_setup = match pin {
+            true => Some((amc_mon_ids(mons), setup.name.clone())),
+            false => None,
+        };
+
+        layouts.remember(&amc_mon_ids(mons), &setup.configs)
+    }
+
+    fn format_status(&self) -> String {
+        format_args!(
+            "Active setup: {}{}\n",
+            match (&self.active_setup, self.layout_restored) {
+                (Some(name), _) => name.as_str(),
+                (None, true) => "none (last layout used with these monitors)",
+                (None, false) => "none (default config)",
+            },
+            match self.pinned_setup {
+                Some(_) => " (pinned)",
+                None => "",
+            }
+        )
+        .to_string()
+    }
+}
+
+/* An X display amc manages, either one given with --display or $DISPLAY */
+struct AmcDisplay {
+    /* As given on the command line, None for $DISPLAY */
+    name: Option<String>,
+    /* One for each managed screen, empty while the X server is gone */
+    screens: Vec<AmcScreen>,
+    /* When to try reconnecting next and how long to wait after that if it fails again */
+    reconnect: Option<(Instant, time::Duration)>,
+}
+
+impl AmcDisplay {
+    fn connect(name: Option<String>) -> amc::Result<AmcDisplay> {
+        let stacks = amc::XCBStack::connect_all(name.as_deref())?;
+
+        Ok(AmcDisplay {
+            name,
+            screens: stacks.into_iter().map(AmcScreen::new).collect(),
+            reconnect: None,
+        })
+    }
+
+    fn display_name(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => env::var("DISPLAY").unwrap_or_default(),
+        }
+    }
+}
+
+/* Whether output and errors need to say which screen they're about */
+fn amc_multi_screen(displays: &[AmcDisplay]) -> bool {
+    displays.len() > 1 || displays.iter().any(|display| display.screens.len() > 1)
+}
+
+/* Runs `f` on every screen we're connected to. With more than one screen, each screen's output
+and errors are prefixed with its name, and screens with no output are left out. Fails if `f`
+failed on any screen. */
+fn amc_for_each_screen(
+    displays: &mut [AmcDisplay],
+    mut f: impl FnMut(&mut AmcScreen) -> amc::Result<String>,
+) -> amc::Result<String> {
+    if !amc_multi_screen(displays) {
+        return match displays.iter_mut().next() {
+            Some(display) if display.screens.is_empty() => {
+                Err(amc::Error::Disconnected(display.display_name()))
+            }
+            Some(display) => f(&mut display.screens[0]),
+            None => Err("amc is waiting for the X server to come back".into()),
+        };
+    }
+
+    let mut out = String::new();
+    let mut errors = Vec::<amc::Error>::new();
+
+    for display in displays.iter_mut() {
+        if display.screens.is_empty() {
+            errors.push(amc::Error::Disconnected(display.display_name()));
+        }
+
+        for screen in &mut display.screens {
+            match f(screen) {
+                Ok(screen_out) if screen_out.is_empty() => (),
+                Ok(screen_out) => {
+                    out += &format_args!("Screen {}:\n", screen.xstack.name()).to_string();
+                    for line in screen_out.lines() {
+                        out += &format_args!("  {}\n", line).to_string();
+                    }
+                }
+                /* Kept as it is, for callers to tell the display needs reconnecting */
+                Err(err @ amc::Error::Connection(_)) => errors.push(err),
+                Err(err) => errors.push(
+                    format_args!("{}: {}", screen.xstack.name(), err)
+                        .to_string()
+                        .into(),
+                ),
+            }
+        }
+    }
+
+    match errors.is_empty() {
+        true => Ok(out),
+        false => Err(amc::Error::many(errors)),
+    }
+}
+
+/* State kept by the polling loop between iterations */
+struct AmcDaemon {
+    config_dir: String,
+    /* Last modification time of the config dir, used to pick up config changes */
+    config_stamp: Option<std::time::SystemTime>,
+    config: AmcConfig,
+    /* Reconfigure on every poll instead of only when the monitors or the config change, on top
+    of the config's enforce setting */
+    enforce: bool,
+    layouts: LayoutState,
+    displays: Vec<AmcDisplay>,
+}
+
+impl AmcDaemon {
+    /* On error, config errors included, the setups we had are kept */
+    fn reload_setups(&mut self) -> amc::Result<()> {
+        self.config_stamp = amc_config_dir_stamp(&self.config_dir);
+        self.config = amc_read_config_from_dir(&self.config_dir)?;
+
+        for display in &mut self.displays {
+            for screen in &mut display.screens {
+                screen.applied_mon_ids = None;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn poll(&mut self, force: bool) -> amc::Result<()> {
+        if amc_config_dir_stamp(&self.config_dir) != self.config_stamp {
+            // The stamp is updated either way, so a broken config only gets reported once
+            self.reload_setups()?;
+        }
+
+        let enforce = self.enforce || self.config.globals.enforce;
+
+        amc_for_each_screen(&mut self.displays, |screen| {
+            screen
+                .poll(&self.config, &mut self.layouts, enforce, force)
+                .map(|_| String::new())
+        })
+        .map(|_| ())
+    }
+
+    /* How long to wait between polls */
+    fn poll_interval(&self) -> time::Duration {
+        self.config.globals.poll_interval
+    }
+
+    /* Drops the screens of every display whose X server went away, returning the display names
+    along with what happened to them */
+    fn drop_lost_displays(&mut self) -> Vec<(String, xcb::ConnError)> {
+        let mut lost = Vec::new();
+
+        for display in &mut self.displays {
+            let err = display
+                .screens
+                .iter()
+                .find_map(|screen| screen.xstack.conn.has_error().err());
+
+            if let Some(err) = err {
+                // Whatever we knew about the old server's outputs is worthless now
+                display.screens.clear();
+                display.reconnect = Some((
+                    Instant::now() + RECONNECT_BACKOFF_MIN,
+                    RECONNECT_BACKOFF_MIN,
+                ));
+                lost.push((display.display_name(), err));
+            }
+        }
+
+        lost
+    }
+
+    /* Tries to reconnect to the displays that are due, backing off further on failure */
+    fn reconnect_displays(&mut self) {
+        for display in &mut self.displays {
+            let backoff = match display.reconnect {
+                Some((at, backoff)) if at <= Instant::now() => backoff,
+                _ => continue,
+            };
+
+            match AmcDisplay::connect(display.name.clone()) {
+                Ok(x) => {
+                    println!("Reconnected to the X server of {}", display.display_name());
+                    *display = x;
+                }
+                Err(_) => {
+                    let backoff = std::cmp::min(backoff * 2, RECONNECT_BACKOFF_MAX);
+                    display.reconnect = Some((Instant::now() + backoff, backoff));
+                }
+            }
+        }
+    }
+
+    fn next_reconnect(&self) -> Option<Instant> {
+        self.displays
+            .iter()
+            .filter_map(|display| display.reconnect.map(|(at, _)| at))
+            .min()
+    }
+
+    /* What the next poll would do with the connected monitors, without doing it. A fresh amc
+    is assumed, so setups pinned or picked with 'next'/'prev' in a running one don't count. */
+    fn dry_run(&mut self, json_output: bool) -> amc::Result<String> {
+        let setups = &self.config.setups;
+        let fallback = self.config.globals.fallback;
+        let layouts = &self.layouts;
+
+        let plan_screen = |screen: &mut AmcScreen| -> amc::Result<(
+            Vec<amc::Monitor>,
+            Option<String>,
+            &'static str,
+            AmcLayoutPlan,
+        )> {
+            let mons = amc::Monitor::get_all_connected(&screen.xstack)?;
+            let setup_name = screen.select_setup(&mons, setups);
+
+            let setup = match &setup_name {
+                Some(name) => setups.iter().find(|setup| setup.name == *name),
+                None => layouts.get(&amc_mon_ids(&mons)),
+            };
+            let source = match (&setup_name, setup) {
+                (Some(_), _) => "setup",
+                (None, Some(_)) => "remembered",
+                (None, None) => "default",
+            };
+
+            let plan = amc_plan_setup_for_mons(&screen.xstack, &mons, setup, fallback, false)?;
+
+            Ok((mons, setup_name, source, plan))
+        };
+
+        if json_output {
+            let screens = amc_screens_json(&mut self.displays, |screen| {
+                let (mons, setup_name, source, plan) = plan_screen(screen)?;
+
+                Ok(vec![
+                    ("source", source.into()),
+                    ("setup", setup_name.into()),
+                    (
+                        "layout",
+                        json::Value::Array(
+                            plan.placements
+                                .iter()
+                                .map(amc_monitor_config_json)
+                                .collect(),
+                        ),
+                    ),
+                    ("screen_size", amc_screen_size_json(&plan.screen)),
+                    (
+                        "changes",
+                        json::Value::Array(
+                            plan.crtc_configs
+                                .iter()
+                                .map(|conf| amc_crtc_config_json(conf, &mons))
+                                .collect(),
+                        ),
+                    ),
+                ])
+            })?;
+
+            return Ok(json::Value::object(vec![("screens", screens)]).to_string() + "\n");
+        }
+
+        amc_for_each_screen(&mut self.displays, |screen| {
+            let (mons, setup_name, source, plan) = plan_screen(screen)?;
+            let mut out = String::new();
+
+            out += &format_args!(
+                "Setup: {}\n",
+                match (&setup_name, source) {
+                    (Some(name), _) => name.as_str(),
+                    (None, "remembered") => "none (last layout used with these monitors)",
+                    (None, _) => "none (default config)",
+                }
+            )
+            .to_string();
+            out += &format_args!(
+                "Screen: {}x{} ({}x{}mm)\n",
+                plan.screen.width, plan.screen.height, plan.screen.mm_width, plan.screen.mm_height
+            )
+            .to_string();
+
+            out += "Layout:\n";
+            for conf in &plan.placements {
+                out += &format_args!(
+                    "  {} ({:x}) at {}x{}, rotation {}\n",
+                    mons.iter()
+                        .find(|mon| mon.id == conf.id)
+                        .map(|mon| mon.name.as_str())
+                        .unwrap_or("?"),
+                    conf.id,
+                    conf.x,
+                    conf.y,
+                    amc_rotation_degrees(conf.rot)
+                )
+                .to_string();
+            }
+
+            match plan.crtc_configs.is_empty() {
+                true => out += "Changes: none\n",
+                false => out += "Changes:\n",
+            }
+            for conf in &plan.crtc_configs {
+                out += &format_args!(
+                    "  crtc {:x}: {} at {}x{}, {}x{}, rotation {}\n",
+                    conf.crtc.resource_id(),
+                    amc_output_names(&conf.outputs, &mons).join(", "),
+                    conf.x,
+                    conf.y,
+                    conf.w,
+                    conf.h,
+                    amc_rotation_degrees(conf.rot)
+                )
+                .to_string();
+            }
+
+            Ok(out)
+        })
+    }
+
+    fn handle_command(&mut self, cmd: ctl::Command) -> amc::Result<String> {
+        // With several screens, setups are only applied where they match
+        let multi = amc_multi_screen(&self.displays);
+
+        match cmd {
+            ctl::Command::Status { json: true } => {
+                let screens = amc_screens_json(&mut self.displays, |screen| {
+                    let mons = amc::Monitor::get_all_connected(&screen.xstack)?;
+
+                    Ok(vec![
+                        ("active_setup", screen.active_setup.clone().into()),
+                        ("pinned", screen.pinned_setup.is_some().into()),
+                        ("layout_restored", screen.layout_restored.into()),
+                        (
+                            "monitors",
+                            json::Value::Array(
+                                mons.iter()
+                                    .map(|mon| amc_monitor_json(mon, &mons, &self.config))
+                                    .collect(),
+                            ),
+                        ),
+                    ])
+                })?;
+
+                Ok(json::Value::object(vec![
+                    ("config_dir", self.config_dir.as_str().into()),
+                    (
+                        "setups",
+                        json::Value::Array(self.config.setups.iter().map(amc_setup_json).collect()),
+                    ),
+                    ("screens", screens),
+                    (
+                        "disconnected_displays",
+                        self.displays
+                            .iter()
+                            .filter(|display| display.screens.is_empty())
+                            .map(|display| display.display_name())
+                            .collect::<Vec<String>>()
+                            .into(),
+                    ),
+                ])
+                .to_string()
+                    + "\n")
+            }
+            ctl::Command::Status { json: false } => {
+                let mut out = String::new();
+
+                out += &format_args!("Config dir: {}\n", self.config_dir).to_string();
+                out += &format_args!(
+                    "Setups: {}\n",
+                    self.config
+                        .setups
+                        .iter()
+                        .map(|setup| setup.name.as_str())
+                        .collect::<Vec<&str>>()
+                        .join(", ")
+                )
+                .to_string();
+                out += &amc_for_each_screen(&mut self.displays, |screen| {
+                    let mons = amc::Monitor::get_all_connected(&screen.xstack)?;
+
+                    Ok(screen.format_status() + &amc_format_monitors(&mons))
+                })?;
+
+                Ok(out)
+            }
+            ctl::Command::Reapply => {
+                self.poll(true)?;
+
+                Ok(String::new())
+            }
+            ctl::Command::Reload => {
+                self.reload_setups()?;
+                self.poll(false)?;
+
+                Ok(format_args!("Loaded {} setup(s)\n", self.config.setups.len()).to_string())
+            }
+            ctl::Command::Apply { setup, pin } => {
+                if !self.config.setups.iter().any(|s| s.name == setup) {
+                    return Err(format_args!("No setup named '{}'", setup)
+                        .to_string()
+                        .into());
+                }
 
-    if let Some(setup) = best_setup {
-        for conf in &setup.configs {
-            let mon = mons.iter().find(|mon| mon.id == conf.id).unwrap();
+                let mut applied = false;
+                let out = amc_for_each_screen(&mut self.displays, |screen| {
+                    let mons = amc::Monitor::get_all_connected(&screen.xstack)?;
+
+                    if multi
+                        && !amc_find_matching_setups_for_mons(
+                            &screen.xstack,
+                            &mons,
+                            &self.config.setups,
+                        )
+                        .iter()
+                        .any(|s| s.name == setup)
+                    {
+                        return Ok(String::new());
+                    }
 
-            let config_applied = mon.apply_config(xstack, conf)?;
+                    screen.apply(&mons, &self.config, &mut self.layouts, &setup, pin)?;
+                    applied = true;
 
-            configs_changed = configs_changed || config_applied;
+                    Ok(String::new())
+                })?;
 
-            match conf.rot {
-                randr::Rotation::ROTATE_0 | randr::Rotation::ROTATE_180 => {
-                    screen_w = std::cmp::max(screen_w, (conf.x as u16) + mon.w);
-                    screen_h = std::cmp::max(screen_h, (conf.y as u16) + mon.h);
+                if !applied {
+                    return Err(format_args!(
+                        "Setup '{}' doesn't match the monitors of any screen",
+                        setup
+                    )
+                    .to_string()
+                    .into());
                 }
-                randr::Rotation::ROTATE_90 | randr::Rotation::ROTATE_270 => {
-                    screen_w = std::cmp::max(screen_w, (conf.x as u16) + mon.h);
-                    screen_h = std::cmp::max(screen_h, (conf.y as u16) + mon.w);
+
+                Ok(out)
+            }
+            ctl::Command::Next | ctl::Command::Prev => {
+                let step = match cmd {
+                    ctl::Command::Next => 1,
+                    _ => -1,
+                };
+
+                let mut cycled = false;
+                let out = amc_for_each_screen(&mut self.displays, |screen| {
+                    let mons = amc::Monitor::get_all_connected(&screen.xstack)?;
+
+                    if multi
+                        && amc_find_matching_setups_for_mons(
+                            &screen.xstack,
+                            &mons,
+                            &self.config.setups,
+                        )
+                        .is_empty()
+                    {
+                        return Ok(String::new());
+                    }
+
+                    cycled = true;
+                    screen.cycle(&mons, &self.config, step)
+                })?;
+
+                if !cycled {
+                    return Err("No setup matches the connected monitors of any screen".into());
                 }
-                _ => unreachable!(),
+
+                Ok(out)
             }
+            ctl::Command::Save => amc_for_each_screen(&mut self.displays, |screen| {
+                let mons = amc::Monitor::get_all_connected(&screen.xstack)?;
 
-            screen_w_mm += mon.w_mm;
-            screen_h_mm += mon.h_mm;
+                self.layouts.save_current(&mons)
+            }),
+            ctl::Command::ListMonitors => amc_for_each_screen(&mut self.displays, |screen| {
+                let mons = amc::Monitor::get_all_connected(&screen.xstack)?;
+
+                Ok(amc_format_monitors_detailed(&mons, &self.config))
+            }),
         }
-    } else {
-        /* If no setup matched what's connected, we mirror each display.
-         * We could rig each display to a single crtc if they
-         * are the same size, mode, etc but I can't be fucked to check all of that.
-         */
-        for mon in mons {
-            let config_applied = mon.apply_config(
-                xstack,
-                &amc::MonitorConfig {
-                    id: mon.id,
-                    x: 0,
-                    y: 0,
-                    rot: randr::Rotation::ROTATE_0,
-                },
-            );
+    }
+}
+
+/* Names of the other monitors driven by the same crtc as `mon`, when mirroring */
+fn amc_crtc_mates(mon: &amc::Monitor, mons: &[amc::Monitor]) -> Vec<String> {
+    mon.crtc_config
+        .iter()
+        .flat_map(|crtc_config| &crtc_config.outputs)
+        .filter(|&&output| output != mon.output)
+        .map(
+            |output| match mons.iter().find(|other| other.output == *output) {
+                Some(other) => other.name.clone(),
+                None => "a disconnected output".to_string(),
+            },
+        )
+        .collect()
+}
 
-            configs_changed = configs_changed || config_applied?;
-            screen_w = std::cmp::max(screen_w, mon.w);
-            screen_h = std::cmp::max(screen_h, mon.h);
-            screen_w_mm = std::cmp::max(screen_w_mm, mon.w_mm);
-            screen_h_mm = std::cmp::max(screen_h_mm, mon.h_mm);
+/* Names of the setups that have `mon` in them */
+fn amc_setups_using<'a>(mon: &amc::Monitor, setups: &'a [MonitorSetup]) -> Vec<&'a str> {
+    setups
+        .iter()
+        .filter(|setup| setup.configs.iter().any(|conf| conf.id == mon.id))
+        .map(|setup| setup.name.as_str())
+        .collect()
+}
+
+/* Every alias naming `mon`, the global ones first */
+fn amc_aliases_of<'a>(mon: &amc::Monitor, config: &'a AmcConfig) -> Vec<&'a str> {
+    let mut names = Vec::<&str>::new();
+
+    for (name, id) in config
+        .globals
+        .aliases
+        .iter()
+        .chain(config.setups.iter().flat_map(|setup| &setup.aliases))
+    {
+        if *id == mon.id && !names.contains(&name.as_str()) {
+            names.push(name);
         }
     }
 
-    if configs_changed {
-        xstack.conn.send_request(&randr::SetScreenSize {
-            window: *xstack.root_window,
-            width: screen_w,
-            height: screen_h,
-            mm_width: screen_w_mm,
-            mm_height: screen_h_mm,
-        });
+    names
+}
+
+fn amc_format_mode(mode: &amc::MonitorMode) -> String {
+    format_args!("{}x{}@{:.2}Hz", mode.width, mode.height, mode.refresh).to_string()
+}
+
+fn amc_format_monitors(mons: &[amc::Monitor]) -> String {
+    if mons.is_empty() {
+        return "No connected monitors\n".to_string();
+    }
+
+    let mut out = "Connected monitors:\n".to_string();
+    for mon in mons {
+        let crtc_mates = amc_crtc_mates(mon, mons);
+
+        out += &format_args!(
+            "  {} ({:x}{}{})\n",
+            mon.name,
+            mon.id,
+            match mon.edid {
+                Some(_) => "",
+                None => ", no EDID: identified by connector and modes",
+            },
+            match crtc_mates.is_empty() {
+                true => String::new(),
+                false =>
+                    format_args!(", shares its crtc with {}", crtc_mates.join(", ")).to_string(),
+            }
+        )
+        .to_string();
     }
 
-    Ok(())
+    out
 }
 
-fn amc_parse_setup_from_conf_str(file_content: &String) -> amc::Result<MonitorSetup> {
-    let mut mon_setup = MonitorSetup {
-        configs: Vec::new(),
-    };
+/* Everything there is to know about the connected monitors when writing setups */
+fn amc_format_monitors_detailed(mons: &[amc::Monitor], config: &AmcConfig) -> String {
+    if mons.is_empty() {
+        return "No connected monitors\n".to_string();
+    }
 
-    for (line_n, mut line) in file_content.lines().map(|l| l.to_string()).enumerate() {
-        line.retain(|c| !c.is_whitespace());
+    let mut out = "Connected monitors:\n".to_string();
+    for mon in mons {
+        out += &format_args!(
+            "  {} ({:x}{})\n",
+            mon.name,
+            mon.id,
+            match mon.is_internal() {
+                true => ", built-in panel",
+                false => "",
+            }
+        )
+        .to_string();
 
-        if line.is_empty() || line.chars().nth(0).unwrap() == '#' {
-            continue;
+        let aliases = amc_aliases_of(mon, config);
+        if !aliases.is_empty() {
+            out += &format_args!("    Aliases: {}\n", aliases.join(", ")).to_string();
         }
 
-        let (lhs, rhs): (&str, &str) = match line.split_once('=') {
-            Some((lhs, rhs)) => {
-                if lhs.is_empty() || rhs.is_empty() {
-                    return Err(format_args!("Invalid config at line {}", line_n)
-                        .to_string()
-                        .into());
-                }
-
-                (lhs, rhs)
+        out += &format_args!(
+            "    EDID: {}\n",
+            match (&mon.edid, mon.edid_info()) {
+                (None, _) => "none, identified by connector and modes".to_string(),
+                (Some(_), None) => "unreadable".to_string(),
+                (Some(_), Some(info)) => format_args!(
+                    "{} {}{}",
+                    info.vendor,
+                    match info.model {
+                        Some(model) => model,
+                        None => format_args!("product {:04x}", info.product_code).to_string(),
+                    },
+                    match info.serial {
+                        Some(serial) => format_args!(", serial {}", serial).to_string(),
+                        None => String::new(),
+                    }
+                )
+                .to_string(),
             }
-            None => {
-                return Err(format_args!("Invalid config at line {}", line_n)
+        )
+        .to_string();
+
+        out += &format_args!(
+            "    Physical size: {}\n",
+            match mon.w_mm != 0 && mon.h_mm != 0 {
+                true => format_args!("{}x{}mm", mon.w_mm, mon.h_mm).to_string(),
+                false => "unknown".to_string(),
+            }
+        )
+        .to_string();
+
+        out += &format_args!(
+            "    Current: {}\n",
+            match (&mon.crtc_config, mon.crtc) {
+                (Some(crtc_config), Some(crtc)) => {
+                    let crtc_mates = amc_crtc_mates(mon, mons);
+
+                    format_args!(
+                        "{} at {}x{}, rotation {}{}{} (crtc {:x}{})",
+                        match mon.find_mode(crtc_config.mode) {
+                            Some(mode) => amc_format_mode(mode),
+                            None => format_args!("mode {:x}", crtc_config.mode.resource_id())
+                                .to_string(),
+                        },
+                        crtc_config.x,
+                        crtc_config.y,
+                        amc_rotation_degrees(crtc_config.rot),
+                        match amc_reflection(crtc_config.rot) {
+                            Some(reflect) => format_args!(", reflected {}", reflect).to_string(),
+                            None => String::new(),
+                        },
+                        match crtc_config.scale == 1.0 {
+                            true => String::new(),
+                            false => format_args!(", scale {}", crtc_config.scale).to_string(),
+                        },
+                        crtc.resource_id(),
+                        match crtc_mates.is_empty() {
+                            true => String::new(),
+                            false =>
+                                format_args!(", shared with {}", crtc_mates.join(", ")).to_string(),
+                        }
+                    )
                     .to_string()
-                    .into())
+                }
+                _ => "off".to_string(),
             }
-        };
+        )
+        .to_string();
+
+        out += &format_args!(
+            "    Preferred mode: {}\n",
+            match mon.modes.iter().find(|mode| mode.preferred) {
+                Some(mode) => amc_format_mode(mode),
+                None => "none".to_string(),
+            }
+        )
+        .to_string();
+
+        out += "    Modes:\n";
+        for mode in &mon.modes {
+            out += &format_args!(
+                "      {}{}\n",
+                amc_format_mode(mode),
+                match (
+                    mode.preferred,
+                    mon.crtc_config.as_ref().map(|conf| conf.mode) == Some(mode.mode)
+                ) {
+                    (true, true) => " (preferred, current)",
+                    (true, false) => " (preferred)",
+                    (false, true) => " (current)",
+                    (false, false) => "",
+                }
+            )
+            .to_string();
+        }
 
-        let mon_id = match u32::from_str_radix(lhs, 16) {
-            Ok(x) => x,
-            Err(_) => {
-                return Err(format_args!("Invalid monitor id at line {}", line_n)
-                    .to_string()
-                    .into())
+        let setup_names = amc_setups_using(mon, &config.setups);
+        out += &format_args!(
+            "    Setups: {}\n",
+            match setup_names.is_empty() {
+                true => "none".to_string(),
+                false => setup_names.join(", "),
             }
-        };
+        )
+        .to_string();
+    }
 
-        let (x, y, rot) = match rhs.split_once(',') {
-            Some((xy, rot)) => {
-                if xy.is_empty() {
-                    return Err(format_args!("Missing monitor position at line {}", line_n)
-                        .to_string()
-                        .into());
-                }
+    out
+}
 
-                if rot.is_empty() {
-                    return Err(format_args!("Missing monitor rotation at line {}", line_n)
-                        .to_string()
-                        .into());
-                }
+fn amc_mode_json(mode: &amc::MonitorMode) -> json::Value {
+    json::Value::object(vec![
+        ("width", mode.width.into()),
+        ("height", mode.height.into()),
+        ("refresh", mode.refresh.into()),
+        ("preferred", mode.preferred.into()),
+    ])
+}
 
-                let rot_n = match rot.parse::<u16>() {
-                    Ok(x) => x,
-                    Err(_) => {
-                        return Err(format_args!("Invalid monitor rotation at line {} (rotation can only have the following values: 0, 90, 180, 270)", line_n)
-                            .to_string()
-                            .into());
-                    }
-                };
+fn amc_monitor_json(mon: &amc::Monitor, mons: &[amc::Monitor], config: &AmcConfig) -> json::Value {
+    json::Value::object(vec![
+        ("id", format_args!("{:x}", mon.id).to_string().into()),
+        ("aliases", amc_aliases_of(mon, config).into()),
+        ("connector", mon.name.as_str().into()),
+        ("internal", mon.is_internal().into()),
+        (
+            "edid",
+            match mon.edid_info() {
+                Some(info) => json::Value::object(vec![
+                    ("vendor", info.vendor.into()),
+                    ("product_code", info.product_code.into()),
+                    ("model", info.model.into()),
+                    ("serial", info.serial.into()),
+                ]),
+                None => json::Value::Null,
+            },
+        ),
+        (
+            "identified_by",
+            match mon.edid {
+                Some(_) => "edid",
+                None => "connector",
+            }
+            .into(),
+        ),
+        (
+            "physical_size_mm",
+            match mon.w_mm != 0 && mon.h_mm != 0 {
+                true => json::Value::object(vec![
+                    ("width", mon.w_mm.into()),
+                    ("height", mon.h_mm.into()),
+                ]),
+                false => json::Value::Null,
+            },
+        ),
+        (
+            "current",
+            match (&mon.crtc_config, mon.crtc) {
+                (Some(crtc_config), Some(crtc)) => json::Value::object(vec![
+                    ("crtc", crtc.resource_id().into()),
+                    ("x", crtc_config.x.into()),
+                    ("y", crtc_config.y.into()),
+                    ("rotation", amc_rotation_degrees(crtc_config.rot).into()),
+                    ("reflect", amc_reflection(crtc_config.rot).into()),
+                    ("scale", crtc_config.scale.into()),
+                    (
+                        "mode",
+                        match mon.find_mode(crtc_config.mode) {
+                            Some(mode) => amc_mode_json(mode),
+                            None => json::Value::Null,
+                        },
+                    ),
+                    ("shared_with", amc_crtc_mates(mon, mons).into()),
+                ]),
+                _ => json::Value::Null,
+            },
+        ),
+        (
+            "preferred_mode",
+            match mon.modes.iter().find(|mode| mode.preferred) {
+                Some(mode) => amc_mode_json(mode),
+                None => json::Value::Null,
+            },
+        ),
+        (
+            "modes",
+            json::Value::Array(mon.modes.iter().map(amc_mode_json).collect()),
+        ),
+        ("setups", amc_setups_using(mon, &config.setups).into()),
+    ])
+}
 
-                if rot_n != 0 && rot_n != 90 && rot_n != 180 && rot_n != 270 {
-                    return Err(format_args!("Invalid monitor rotation at line {} (rotation can only have the following values: 0, 90, 180, 270)", line_n)
-                            .to_string()
-                            .into());
+fn amc_monitor_config_json(conf: &amc::MonitorConfig) -> json::Value {
+    json::Value::object(vec![
+        ("id", format_args!("{:x}", conf.id).to_string().into()),
+        ("x", conf.x.into()),
+        ("y", conf.y.into()),
+        ("rotation", amc_rotation_degrees(conf.rot).into()),
+        ("reflect", amc_reflection(conf.rot).into()),
+        (
+            "mode",
+            match conf.mode {
+                Some((w, h)) => {
+                    json::Value::object(vec![("width", w.into()), ("height", h.into())])
                 }
+                None => json::Value::Null,
+            },
+        ),
+        ("rate", conf.rate.into()),
+        ("scale", conf.scale.into()),
+        ("primary", conf.primary.into()),
+        ("off", conf.off.into()),
+    ])
+}
 
-                let (x, y) = match xy.split_once('x') {
-                    Some((x, y)) => {
-                        if x.is_empty() || y.is_empty() {
-                            return Err(format_args!(
-                                "Invalid monitor position at line {}",
-                                line_n
-                            )
-                            .to_string()
-                            .into());
-                        }
+fn amc_setup_json(setup: &MonitorSetup) -> json::Value {
+    json::Value::object(vec![
+        ("name", setup.name.as_str().into()),
+        ("extends", setup.extends.clone().into()),
+        ("priority", setup.priority.into()),
+        (
+            "monitors",
+            json::Value::Array(setup.configs.iter().map(amc_monitor_config_json).collect()),
+        ),
+        (
+            "dpi",
+            match setup.dpi {
+                amc::DpiPolicy::Primary => "primary".into(),
+                amc::DpiPolicy::Average => "average".into(),
+                amc::DpiPolicy::Fixed(dpi) => dpi.into(),
+            },
+        ),
+        (
+            "xresources",
+            json::Value::Object(
+                setup
+                    .xresources
+                    .iter()
+                    .map(|(name, value)| (name.clone(), value.as_str().into()))
+                    .collect(),
+            ),
+        ),
+        ("display", setup.display.clone().into()),
+        ("screen", setup.screen.into()),
+        ("host", setup.host.clone().into()),
+        (
+            "panels",
+            match &setup.panels {
+                Some(panels) => panels
+                    .iter()
+                    .map(|id| format_args!("{:x}", id).to_string())
+                    .collect::<Vec<String>>()
+                    .into(),
+                None => json::Value::Null,
+            },
+        ),
+    ])
+}
 
-                        let x_n = match x.parse::<u16>() {
-                            Ok(x) => x,
-                            Err(_) => {
-                                return Err(format_args!(
-                                    "Invalid monitor position at line {}",
-                                    line_n
-                                )
-                                .to_string()
-                                .into());
-                            }
-                        };
-
-                        let y_n = match y.parse::<u16>() {
-                            Ok(x) => x,
-                            Err(_) => {
-                                return Err(format_args!(
-                                    "Invalid monitor position at line {}",
-                                    line_n
-                                )
-                                .to_string()
-                                .into());
-                            }
-                        };
+fn amc_globals_json(globals: &AmcGlobals) -> json::Value {
+    json::Value::object(vec![
+        (
+            "fallback",
+            match globals.fallback {
+                AmcFallback::Mirror => "mirror",
+                AmcFallback::Extend => "extend",
+                AmcFallback::Keep => "none",
+            }
+            .into(),
+        ),
+        ("poll_interval", globals.poll_interval.as_secs_f64().into()),
+        ("hook", globals.hook.clone().into()),
+        ("enforce", globals.enforce.into()),
+        (
+            "aliases",
+            json::Value::Object(
+                globals
+                    .aliases
+                    .iter()
+                    .map(|(name, id)| (name.clone(), format_args!("{:x}", id).to_string().into()))
+                    .collect(),
+            ),
+        ),
+    ])
+}
 
-                        (x_n, y_n)
-                    }
-                    None => {
-                        return Err(format_args!("Invalid config at line {}", line_n)
-                            .to_string()
-                            .into())
-                    }
-                };
+fn amc_screen_size_json(screen: &amc::ScreenSize) -> json::Value {
+    json::Value::object(vec![
+        ("width", screen.width.into()),
+        ("height", screen.height.into()),
+        ("width_mm", screen.mm_width.into()),
+        ("height_mm", screen.mm_height.into()),
+    ])
+}
 
-                (x, y, rot_n)
-            }
-            None => {
-                return Err(format_args!("Invalid config at line {}", line_n)
-                    .to_string()
-                    .into())
-            }
-        };
+fn amc_crtc_config_json(conf: &amc::CrtcConfig, mons: &[amc::Monitor]) -> json::Value {
+    json::Value::object(vec![
+        ("crtc", conf.crtc.resource_id().into()),
+        ("enabled", (!conf.mode.is_none()).into()),
+        ("x", conf.x.into()),
+        ("y", conf.y.into()),
+        ("width", conf.w.into()),
+        ("height", conf.h.into()),
+        ("rotation", amc_rotation_degrees(conf.rot).into()),
+        ("reflect", amc_reflection(conf.rot).into()),
+        ("scale", conf.scale.into()),
+        ("outputs", amc_output_names(&conf.outputs, mons).into()),
+    ])
+}
 
-        mon_setup.configs.push(amc::MonitorConfig {
-            id: mon_id,
-            x: x as i16,
-            y: y as i16,
-            rot: match rot {
-                0 => randr::Rotation::ROTATE_0,
-                90 => randr::Rotation::ROTATE_90,
-                180 => randr::Rotation::ROTATE_180,
-                270 => randr::Rotation::ROTATE_270,
-                _ => unreachable!(),
+fn amc_output_names(outputs: &[randr::Output], mons: &[amc::Monitor]) -> Vec<String> {
+    outputs
+        .iter()
+        .map(
+            |output| match mons.iter().find(|mon| mon.output == *output) {
+                Some(mon) => mon.name.clone(),
+                None => format_args!("{:x}", output.resource_id()).to_string(),
             },
-        });
+        )
+        .collect()
+}
+
+/* An array with an object for each screen we're connected to, holding its display, its screen
+number and whatever `f` adds */
+fn amc_screens_json(
+    displays: &mut [AmcDisplay],
+    mut f: impl FnMut(&mut AmcScreen) -> amc::Result<Vec<(&'static str, json::Value)>>,
+) -> amc::Result<json::Value> {
+    let mut screens = Vec::<json::Value>::new();
+
+    for screen in displays.iter_mut().flat_map(|display| &mut display.screens) {
+        let mut fields = vec![
+            ("display", screen.xstack.display.as_str().into()),
+            ("screen", screen.xstack.screen_num.into()),
+        ];
+        fields.extend(f(screen)?);
+
+        screens.push(json::Value::object(fields));
     }
 
-    Ok(mon_setup)
+    Ok(json::Value::Array(screens))
 }
 
-fn amc_read_setups_from_dir(dir: &String) -> amc::Result<Vec<MonitorSetup>> {
-    match std::fs::exists(dir) {
-        Ok(true) => (),
-        Ok(false) => {
-            if let Err(err) = std::fs::create_dir(dir) {
-                return Err(
-                    format_args!("Could not create config dir '{}'\n  {}", dir, err)
-                        .to_string()
-                        .into(),
-                );
-            };
+fn amc_error_json(err: &amc::Error) -> json::Value {
+    match err {
+        amc::Error::Config {
+            file,
+            line,
+            column,
+            length,
+            snippet,
+            msg,
+        } => json::Value::object(vec![
+            ("file", file.as_str().into()),
+            ("line", (*line as u32).into()),
+            ("column", (*column as u32).into()),
+            ("length", (*length as u32).into()),
+            ("snippet", snippet.as_str().into()),
+            ("message", msg.as_str().into()),
+        ]),
+        err => json::Value::object(vec![
+            ("file", json::Value::Null),
+            ("line", json::Value::Null),
+            ("column", json::Value::Null),
+            ("length", json::Value::Null),
+            ("snippet", json::Value::Null),
+            ("message", err.to_string().into()),
+        ]),
+    }
+}
+
+/* Reads the config dir the way amc would and reports on it, returning whether it's usable */
+fn amc_check_config(config_dir: &str, json_output: bool) -> bool {
+    let result = amc_read_config_from_dir(config_dir);
+
+    if json_output {
+        let (global, setups, errors) = match &result {
+            Ok(config) => (
+                amc_globals_json(&config.globals),
+                config.setups.iter().map(amc_setup_json).collect(),
+                Vec::new(),
+            ),
+            Err(err) => (
+                json::Value::Null,
+                Vec::new(),
+                err.errors().into_iter().map(amc_error_json).collect(),
+            ),
+        };
+
+        println!(
+            "{}",
+            json::Value::object(vec![
+                ("config_dir", config_dir.into()),
+                ("ok", result.is_ok().into()),
+                ("global", global),
+                ("setups", json::Value::Array(setups)),
+                ("errors", json::Value::Array(errors)),
+            ])
+        );
+
+        return result.is_ok();
+    }
+
+    match result {
+        Ok(AmcConfig { setups, .. }) => {
+            println!(
+                "Config dir '{}' is fine, {} setup(s): {}",
+                config_dir,
+                setups.len(),
+                setups
+                    .iter()
+                    .map(|setup| setup.name.as_str())
+                    .collect::<Vec<&str>>()
+                    .join(", ")
+            );
+            true
         }
         Err(err) => {
-            return Err(format_args!(
-                "Could not check existance of config dir '{}'\n  {}",
-                dir, err
-            )
-            .to_string()
-            .into())
-        }
-    };
+            let errors = err.errors();
 
-    let files = match std::fs::read_dir(dir) {
-        Ok(files) => files.collect::<Result<Vec<_>, std::io::Error>>()?, // why tf can this even be Err?
-        Err(err) => {
-            return Err(
-                format_args!("Could not list files in dir '{}'\n  {}", dir, err)
-                    .to_string()
-                    .into(),
-            )
+            for (i, err) in errors.iter().enumerate() {
+                if i > 0 {
+                    println!();
+                }
+                println_error!(err);
+            }
+
+            if errors.len() > 1 {
+                println!(
+                    "\nFound {} errors in config dir '{}'",
+                    errors.len(),
+                    config_dir
+                );
+            }
+            false
         }
-    };
+    }
+}
 
-    let mut setups = Vec::<MonitorSetup>::new();
+/* Answers control socket requests with `handler` for up to `duration`. Stops early if the
+connection to the X server is lost so the caller gets to deal with it. */
+fn amc_serve_ctl(
+    ctl_server: &ctl::Server,
+    duration: time::Duration,
+    mut handler: impl FnMut(ctl::Command) -> amc::Result<String>,
+) {
+    let deadline = Instant::now() + duration;
+    while let Some(timeout) = deadline.checked_duration_since(Instant::now()) {
+        if !ctl_server.wait(timeout) {
+            break;
+        }
 
-    for file in files {
-        let file_content = match std::fs::read_to_string(file.path()) {
+        let (stream, cmd) = match ctl_server.accept() {
             Ok(x) => x,
             Err(err) => {
-                return Err(format_args!(
-                    "Could not read config file '{}'\n  {}",
-                    file.path().to_str().unwrap(),
-                    err
-                )
-                .to_string()
-                .into())
+                println_error!(err);
+                continue;
             }
         };
 
-        match amc_parse_setup_from_conf_str(&file_content) {
-            Ok(setup) => {
-                setups.push(setup);
-            }
-            Err(err) => {
-                return Err(format_args!(
-                    "Could not parse config file '{}'\n  {}",
-                    file.path().to_str().unwrap(),
-                    err
-                )
-                .to_string()
-                .into())
-            }
+        let result = cmd.and_then(&mut handler);
+
+        if result.as_ref().is_err_and(|err| err.lost_connection()) {
+            ctl::reply(
+                stream,
+                Err("amc lost the connection to the X server".into()),
+            );
+            break;
         }
-    }
 
-    Ok(setups)
+        ctl::reply(stream, result);
+    }
 }
 
-fn help(bin_path: &String, error: Option<&String>) {
-    if error.is_some() {
-        println!("{}: {}.", bin_path, error.unwrap());
+fn help(bin_path: &str, error: Option<&str>) {
+    if let Some(error) = error {
+        println!("{}: {}.", bin_path, error);
         println!("Try '{} --help' for more information.", bin_path);
         return;
     }
 
-    println!("Usage: {} [options]", bin_path);
+    println!("Usage: {} [options] [command]", bin_path);
     println!("(Connector name independent) Auto Monitor Configurator for X11");
-    println!("");
+    println!();
     println!("options:");
     println!("  -h, --help            Print this message and exit");
     println!("  -c, --config-dir      Path to config dir from where to grab monitor configs (By default $XDG_CONFIG_HOME/amc)");
     println!("  -p, --print-monitors  Print information on all connected monitors (helpful for configuring)");
+    println!("      --format          Output format of --print-monitors, --check, --dry-run and 'status': \n                        'text' (default) or 'json'");
+    println!("      --json            Same as '--format json'");
+    println!("      --check           Check the config dir for errors and exit");
+    println!("      --dry-run         Print what amc would do with the connected monitors, without doing it");
     println!("  -d, --daemon          Start amc as a daemon");
+    println!(
+        "  -s, --socket          Path to the control socket (By default $XDG_RUNTIME_DIR/amc.sock)"
+    );
+    println!(
+        "  -P, --pin             With 'apply', keep the setup until the connected monitors change"
+    );
+    println!(
+        "  -e, --enforce         Reapply the setup on every poll, reverting changes made by hand"
+    );
+    println!("      --display         X display to manage, can be given more than once (By default $DISPLAY). \n                        Every screen of the display is managed unless one is named, e.g. ':0.1'");
+    println!("      --no-reconnect    Exit when the connection to the X server is lost instead of waiting \n                        for it to come back");
+    println!("\ncommands (sent to an already running amc through its control socket):");
+    println!("  status                Print the active setup and the connected monitors");
+    println!("  reapply               Reconfigure every monitor, even those that look configured");
+    println!("  reload                Re-read the setups from the config dir and apply them");
+    println!("  apply <setup>         Apply the setup with the given name (its section's or config file's name). If no amc is \n                        running the setup is applied directly, otherwise the running amc keeps it until the \n                        connected monitors or the config change (or on every poll with --enforce). With \n                        --pin, it is only dropped once the connected monitors change");
+    println!("  next, prev            Switch to the next/previous setup matching the connected monitors. The choice is \n                        remembered for as long as amc runs, every time the same monitors get connected");
+    println!("  save                  Remember the current layout (e.g. after tweaking it with xrandr) for the connected \n                        monitors. It gets restored whenever they're connected and no setup matches them. \n                        Works without a running amc too");
+    println!("  list-monitors         Print the monitors connected to the running amc's X server");
+    println!("  init <setup>          Write a setup for the connected monitors to <setup>.conf in the config dir, \n                        placed next to each other, for you to edit. Existing files are left alone");
     println!("\nConfiguration:");
     println!("  amc matches and configures monitors based on 'setups'. Setups define a certain configuration of \n  one or more monitors. Configuration only happens in an integral fashion, meaning that either a \n  setup's configuration exactly matches what is plugged in and everything gets configured as \n  specified in the config file, or nothing gets matched and we set a defeault config for every monitor \n  that's plugged in. The default is placing each monitor at 0x0, no rotation & best available mode \n  (mirroring each other).");
     println!("\n  For each monitor setup you have, you'll have to create a separate config file detailing that setup's \n  configuration and place it inside amc's config dir.");
     println!("\n  Configs must end in '.conf' and have the following structure:");
-    println!("    <monitor id> = <x>x<y>, <rotation degrees>");
+    println!("    <monitor id> = <x>x<y>, <rotation degrees>[, <option>...]");
     println!("    ... Repeat that for every monitor in that setup ...");
+    println!("\n  Monitor options:\n    mode=<w>x<h>     use that mode instead of the best one\n    rate=<hz>        pick the mode with that refresh rate\n    scale=<factor>   cover <factor> times as many pixels of the screen, like 'xrandr --scale'\n    reflect=<x|y|xy> mirror the picture along that axis\n    primary          make the monitor the randr primary output\n  A monitor can also be turned off with '<monitor id> = off', and 'priority = <number>' makes a setup \n  win over matching setups with a lower priority, whatever the number of monitors they cover.");
+    println!("\n  Config files starting with a 'version = 2' line can hold several setups, each in its own \n  '[setup <name>]' section written like the above, along with a '[global]' section for settings \n  that apply to amc as a whole:\n    fallback = <mirror|extend|none>  what to do when nothing matches (default: mirror)\n    poll-interval = <seconds>        how often to check the monitors (default: 3)\n    hook = <command>                 shell command run after a layout got applied, with $AMC_SETUP, \n                                     $AMC_DISPLAY and $AMC_MONITORS set\n    enforce = <true|false>           same as --enforce");
+    println!("\n  Monitor ids can be given names with 'alias <name> = <monitor id>' lines and written by name in \n  setups. Aliases in a '[global]' section work in every file, those before the first section of a \n  file in its setups, and those in a setup in that setup only. 'amc -p' shows each monitor's aliases.");
+    println!("\n  Setups can be limited to a machine with 'host = <hostname>' and/or 'panels = <monitor id>[, ...]', \n  the ids of its built-in panels (all of those connected). Files in the config dir's \n  'hosts/<hostname>' dir are only read on that machine, and their setups replace those with the \n  same name.");
+    println!("\n  A setup with an 'extends = <setup>' line starts off as a copy of that setup (from any file) and \n  only needs the monitors and settings that differ, e.g. '<monitor id> = off' for a closed lid. Its \n  priority, dpi, display, screen, host, panels, X resources and aliases are inherited too.");
+    println!("\n  Lines starting with '#' are comments, and so is anything after a '#' following a space. X resource \n  values are taken as written, as they can have '#' in them (e.g. colors).");
     println!(
         "\n  You can get the id of each connected monitor in parenthesis by running '{} -p'.",
         bin_path,
     );
+    println!("\n  When no setup matches, the last layout applied or saved with 'save' for the connected monitors is \n  restored before resorting to the fallback. Those are kept in $XDG_STATE_HOME/amc/layouts.");
+    println!("\n  Positions can be negative (e.g. a monitor left of another one at 0x0), the layout is shifted so that \n  its top left corner ends up at 0x0.");
     println!("\n  Rotation can only be: 0, 90, 180 or 270");
+    println!("\n  A setup can also pick how the screen's physical size (and with it the dpi X clients see) is \n  derived with a 'dpi = <policy>' line, where the policy is one of:\n    primary   the dpi of the primary monitor (default)\n    average   the dpi of each monitor, averaged over the pixels they cover\n    <number>  a fixed dpi, e.g. 'dpi = 96'");
+    println!("\n  When managing more than one display or screen, a setup can be limited to some of them with \n  'display = <name>' (e.g. ':1', or ':1.0' for a single screen) and/or 'screen = <number>' lines. \n  Setups without them are considered everywhere.");
+    println!("\n  X resources to set once the setup is applied go in the setup file too, written like xrdb takes them:\n    Xft.dpi: 192\n    Xcursor.size: 48\n  They're merged into the root window's RESOURCE_MANAGER property, so only clients started afterwards \n  pick them up. Resources are not reset when switching setups, so set the same ones in every setup.");
     println!("\nWhy:");
     println!("  Because my fuckass Thinkpad Dock Gen 2 randomly changes it's connector names even if \n  the physical connections haven't been touched. This tool configures monitors based \n  on their EDIDs and doesn't care about which ports they are plugged into.");
     println!("  Also because I wanted to learn some rust");
@@ -395,8 +2151,20 @@ fn main() {
         },
     };
 
+    let mut socket_path = ctl::default_socket_path();
     let mut daemon = false;
     let mut print_monitors = false;
+    let mut pin = false;
+    let mut enforce = false;
+    let mut reconnect = true;
+    let mut display_names = Vec::<String>::new();
+    let mut json_output = false;
+    let mut check = false;
+    let mut dry_run = false;
+    let mut command_line: Option<String> = None;
+    let mut expect_setup_name = false;
+    let mut init_name: Option<String> = None;
+    let mut expect_init_name = false;
 
     let mut i = 1;
     while i < args.len() {
@@ -405,7 +2173,7 @@ fn main() {
                 help(&args[0], None);
                 exit(0);
             }
-            "-c" | "--config" => {
+            "-c" | "--config" | "-s" | "--socket" | "--display" | "--format" => {
                 if i + 1 >= args.len() {
                     help(
                         &args[0],
@@ -417,14 +2185,76 @@ fn main() {
                 }
 
                 i += 1;
-                config_dir = args[i].to_string();
+                match args[i - 1].as_str() {
+                    "-c" | "--config" => config_dir = args[i].to_string(),
+                    "--display" => display_names.push(args[i].to_string()),
+                    "--format" => {
+                        json_output = match args[i].as_str() {
+                            "text" => false,
+                            "json" => true,
+                            format => {
+                                help(
+                                    &args[0],
+                                    Some(
+                                        &format_args!(
+                                            "Invalid format '{}' (it can be 'text' or 'json')",
+                                            format
+                                        )
+                                        .to_string(),
+                                    ),
+                                );
+                                exit(1);
+                            }
+                        }
+                    }
+                    _ => socket_path = args[i].to_string(),
+                }
             }
             "-p" | "--print-monitors" => {
                 print_monitors = true;
             }
+            "--json" => {
+                json_output = true;
+            }
+            "--check" => {
+                check = true;
+            }
+            "--dry-run" => {
+                dry_run = true;
+            }
             "-d" | "--daemon" => {
                 daemon = true;
             }
+            "-P" | "--pin" => {
+                pin = true;
+            }
+            "-e" | "--enforce" => {
+                enforce = true;
+            }
+            "--no-reconnect" => {
+                reconnect = false;
+            }
+            "status" | "reapply" | "reload" | "next" | "prev" | "save" | "list-monitors"
+                if command_line.is_none() =>
+            {
+                command_line = Some(args[i].to_string());
+            }
+            "apply" if command_line.is_none() => {
+                // The setup name comes as the next positional argument
+                command_line = Some(args[i].to_string());
+                expect_setup_name = true;
+            }
+            setup_name if expect_setup_name && !setup_name.starts_with('-') => {
+                command_line = Some(format_args!("apply {}", setup_name).to_string());
+                expect_setup_name = false;
+            }
+            "init" if command_line.is_none() && init_name.is_none() => {
+                expect_init_name = true;
+            }
+            setup_name if expect_init_name && !setup_name.starts_with('-') => {
+                init_name = Some(setup_name.to_string());
+                expect_init_name = false;
+            }
             invalid_arg => {
                 help(
                     &args[0],
@@ -437,41 +2267,201 @@ fn main() {
         i += 1;
     }
 
-    let (conn, screen_num) = match xcb::Connection::connect(None) {
-        Ok(x) => x,
-        Err(_) => die!("Could not connect to X server"),
+    if expect_init_name {
+        help(&args[0], Some("Command 'init' requires a setup name"));
+        exit(1);
+    }
+
+    if init_name.is_some() && command_line.is_some() {
+        help(
+            &args[0],
+            Some("Command 'init' can't be combined with other commands"),
+        );
+        exit(1);
+    }
+
+    let mut command = match command_line.map(|line| ctl::Command::parse(&line)) {
+        Some(Ok(x)) => Some(x),
+        Some(Err(err)) => {
+            help(&args[0], Some(&err.to_string()));
+            exit(1);
+        }
+        None => None,
+    };
+
+    if let Some(ctl::Command::Apply { pin: cmd_pin, .. }) = &mut command {
+        *cmd_pin = pin;
+    } else if pin {
+        help(
+            &args[0],
+            Some("Option '--pin' can only be used with 'apply'"),
+        );
+        exit(1);
+    }
+
+    if let Some(ctl::Command::Status { json }) = &mut command {
+        *json = json_output;
+    } else if json_output && !print_monitors && !check && !dry_run {
+        help(
+            &args[0],
+            Some("JSON output is only available for '--print-monitors', '--check', '--dry-run' and 'status'"),
+        );
+        exit(1);
+    }
+
+    if check {
+        exit(match amc_check_config(&config_dir, json_output) {
+            true => 0,
+            false => 1,
+        });
+    }
+
+    // These work without a running amc by doing the job ourselves
+    let run_locally = match &command {
+        Some(ctl::Command::Apply { pin, .. }) if !ctl::is_running(&socket_path) => {
+            if *pin {
+                die!("Pinning a setup requires a running amc");
+            }
+
+            true
+        }
+        Some(ctl::Command::Save) => !ctl::is_running(&socket_path),
+        _ => false,
     };
-    let root_screen = conn.get_setup().roots().nth(screen_num as usize).unwrap();
-    let root_window = root_screen.root();
 
-    let xstack = amc::XCBStack {
-        conn: &conn,
-        root_screen: root_screen,
-        root_window: &root_window,
+    if let Some(cmd) = command.as_ref().filter(|_| !run_locally) {
+        match ctl::request(&socket_path, cmd) {
+            Ok(out) => print!("{}", out),
+            Err(err) => die!(err),
+        }
+        exit(0);
+    }
+
+    let display_names = match display_names.is_empty() {
+        true => vec![None],
+        false => display_names.into_iter().map(Some).collect(),
     };
 
+    let mut displays = Vec::<AmcDisplay>::new();
+    for name in display_names {
+        match AmcDisplay::connect(name) {
+            Ok(x) => displays.push(x),
+            Err(err) => die!(format_args!("Could not connect to X server\n  {}", err)),
+        }
+    }
+
     if print_monitors {
-        let mons = match amc::Monitor::get_all_connected(&xstack) {
+        // Only to tell the aliases and setups of each monitor, so a broken config doesn't get in
+        // the way
+        let config = match amc_read_config_from_dir(&config_dir) {
             Ok(x) => x,
+            Err(err) => {
+                if !json_output {
+                    println_error!(err);
+                }
+                AmcConfig::default()
+            }
+        };
+
+        if json_output {
+            match amc_screens_json(&mut displays, |screen| {
+                let mons = amc::Monitor::get_all_connected(&screen.xstack)?;
+
+                Ok(vec![(
+                    "monitors",
+                    json::Value::Array(
+                        mons.iter()
+                            .map(|mon| amc_monitor_json(mon, &mons, &config))
+                            .collect(),
+                    ),
+                )])
+            }) {
+                Ok(screens) => println!("{}", json::Value::object(vec![("screens", screens)])),
+                Err(err) => die!(err),
+            }
+            exit(0);
+        }
+
+        match amc_for_each_screen(&mut displays, |screen| {
+            let mons = amc::Monitor::get_all_connected(&screen.xstack)?;
+
+            Ok(amc_format_monitors_detailed(&mons, &config))
+        }) {
+            Ok(out) => print!("{}", out),
             Err(err) => die!(err),
         };
+        exit(0);
+    }
 
-        if mons.len() == 0 {
-            println!("No connected monitors");
-        } else {
-            println!("Connected monitors:");
-            for mon in mons {
-                println!("  {} ({:x})", mon.name, mon.id)
+    if let Some(name) = &init_name {
+        let mut mons = Vec::<amc::Monitor>::new();
+        for screen in displays.iter().flat_map(|display| &display.screens) {
+            let screen_mons = match amc::Monitor::get_all_connected(&screen.xstack) {
+                Ok(x) => x,
+                Err(err) => die!(err),
+            };
+
+            if !mons.is_empty() && !screen_mons.is_empty() {
+                die!("Monitors are connected to more than one screen, pick one with --display");
             }
+            mons.extend(screen_mons);
+        }
+
+        match amc_init_setup(&config_dir, name, &mons) {
+            Ok(out) => print!("{}", out),
+            Err(err) => die!(err),
         }
         exit(0);
     }
 
-    let mon_setups = match amc_read_setups_from_dir(&config_dir) {
+    let config = match amc_read_config_from_dir(&config_dir) {
         Ok(x) => x,
         Err(err) => die!(err),
     };
 
+    // Losing the remembered layouts isn't worth refusing to start over
+    let state_path = LayoutState::default_path();
+    let layouts = match LayoutState::load(&state_path) {
+        Ok(x) => x,
+        Err(err) => {
+            println_error!(format_args!(
+                "{}\nIt will be moved to '{}.bad' once a layout gets remembered",
+                err, state_path
+            ));
+            LayoutState {
+                path: state_path,
+                layouts: Vec::new(),
+                unreadable: true,
+            }
+        }
+    };
+
+    let mut amc_daemon = AmcDaemon {
+        config_stamp: amc_config_dir_stamp(&config_dir),
+        config_dir,
+        config,
+        enforce,
+        layouts,
+        displays,
+    };
+
+    if dry_run {
+        match amc_daemon.dry_run(json_output) {
+            Ok(out) => print!("{}", out),
+            Err(err) => die!(err),
+        }
+        exit(0);
+    }
+
+    // Without a running amc to send it to, we carry out the command ourselves
+    if let Some(cmd) = command {
+        match amc_daemon.handle_command(cmd) {
+            Ok(out) => print!("{}", out),
+            Err(err) => die!(err),
+        }
+        exit(0);
+    }
+
     // FIXME: this crashes when i run amc at dwm startup?
     // if mon_setups.len() == 0 {
     //     println!(
@@ -482,6 +2472,12 @@ fn main() {
     //     println!("Found {} setup(s)", mon_setups.len());
     // }
 
+    // Bound before daemonizing so the user gets to see if another amc is already running
+    let ctl_server = match ctl::Server::bind(&socket_path) {
+        Ok(x) => x,
+        Err(err) => die!(err),
+    };
+
     // We printed the stuff the user might want to see, so we can detach
     if daemon {
         match unsafe { libc::fork() } {
@@ -501,28 +2497,39 @@ fn main() {
     }
 
     loop {
-        /* I can't get the XRRScreenChangeNotify event to fire (if that's even the right one),
-        so polling it is */
-        defer!(std::thread::sleep(time::Duration::from_secs(3)));
-
-        let mons = match amc::Monitor::get_all_connected(&xstack) {
-            Ok(x) => x,
-            Err(err) => {
-                if conn.has_error().is_err() {
-                    die!("X connection closed");
+        amc_daemon.reconnect_displays();
+
+        /* Whether and when to retry is decided by AmcScreen::poll, losing the X server is dealt
+        with right after */
+        if let Err(err) = amc_daemon.poll(false) {
+            /* Lost connections are reported by drop_lost_displays, and displays waiting for
+            their X server once when it went away */
+            for err in err.errors() {
+                match err {
+                    amc::Error::Connection(_) | amc::Error::Disconnected(_) => (),
+                    err => println_error!(err),
                 }
-
-                println_error!(err);
-                continue;
             }
-        };
+        }
 
-        if let Err(err) = amc_apply_best_setup_for_mons(&xstack, &mons, &mon_setups) {
-            if conn.has_error().is_err() {
-                die!("X connection closed");
+        for (display_name, err) in amc_daemon.drop_lost_displays() {
+            if !reconnect {
+                die!(amc::Error::Connection(err));
             }
 
-            println_error!(err);
-        };
+            println_error!(format_args!(
+                "Lost the connection to the X server of {}, waiting for it to come back\n  {}",
+                display_name,
+                amc::Error::Connection(err)
+            ));
+        }
+
+        // Serve the control socket until it's time to poll again or to retry connecting
+        let mut serve_for = amc_daemon.poll_interval();
+        if let Some(at) = amc_daemon.next_reconnect() {
+            serve_for = std::cmp::min(serve_for, at.saturating_duration_since(Instant::now()));
+        }
+
+        amc_serve_ctl(&ctl_server, serve_for, |cmd| amc_daemon.handle_command(cmd));
     }
 }